@@ -0,0 +1,157 @@
+use super::palette::{self, ParseError};
+use crate::img::Color;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// The color that stands for transparency in sprites
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SpriteColor {
+    /// Fully transparent pixels
+    Transparent,
+    /// A given color
+    Color(Color),
+    /// The color at this index in the input image's palette
+    Index(u8),
+}
+
+/// The color put in slot 0 of every palette
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SharedColor {
+    /// The color used by the most pixels
+    MostFrequent,
+    Color(Color),
+}
+
+/// Parses a single color, in any of the textual palette spec's formats.
+fn parse_color(spec: &str) -> Result<Color, ColorSpecError> {
+    let pal = palette::parse(spec.chars())?;
+    if pal.nb_palettes() != 1 || pal.palette_len(0) != 1 {
+        return Err(ColorSpecError::NotOneColor);
+    }
+    Ok(pal[0][0].clone())
+}
+
+impl FromStr for SpriteColor {
+    type Err = ColorSpecError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+
+        // `index:n` picks a color from the image's palette
+        if let Some(index) = spec.strip_prefix("index:") {
+            return index
+                .trim()
+                .parse()
+                .map(SpriteColor::Index)
+                .map_err(|_| ColorSpecError::BadIndex(index.to_string()));
+        }
+
+        parse_color(spec).map(SpriteColor::Color)
+    }
+}
+
+impl FromStr for SharedColor {
+    type Err = ColorSpecError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.trim() {
+            "most-frequent" => Ok(SharedColor::MostFrequent),
+            spec => parse_color(spec).map(SharedColor::Color),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ColorSpecError {
+    BadIndex(String),
+    NotOneColor,
+    Parse(ParseError),
+}
+
+impl Display for ColorSpecError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        use ColorSpecError::*;
+
+        match self {
+            BadIndex(index) => write!(
+                fmt,
+                "Palette index \"{}\" is not a number between 0 and 255",
+                index
+            ),
+            NotOneColor => write!(fmt, "Exactly one color must be specified"),
+            Parse(err) => err.fmt(fmt),
+        }
+    }
+}
+
+impl error::Error for ColorSpecError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use ColorSpecError::*;
+
+        match self {
+            BadIndex(..) | NotOneColor => None,
+            Parse(err) => Some(err),
+        }
+    }
+}
+
+impl From<ParseError> for ColorSpecError {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index() {
+        assert_eq!(
+            "index:0".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Index(0)
+        );
+        assert_eq!(
+            "index: 255".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Index(255)
+        );
+        assert!("index:256".parse::<SpriteColor>().is_err());
+        assert!("index:".parse::<SpriteColor>().is_err());
+    }
+
+    #[test]
+    fn color() {
+        assert_eq!(
+            "#ff00ff".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Color(Color::FILLER)
+        );
+        assert_eq!(
+            "$7C1F".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Color(Color::from_rgb555(0x7C1F, None))
+        );
+        // Short hex colors made only of decimal digits are still colors
+        assert_eq!(
+            "#000".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Color(Color::new((0, 0, 0, 255), None))
+        );
+        assert_eq!(
+            "#123".parse::<SpriteColor>().unwrap(),
+            SpriteColor::Color(Color::new((0x11, 0x22, 0x33, 255), None))
+        );
+        assert!("#fff,#000".parse::<SpriteColor>().is_err());
+    }
+
+    #[test]
+    fn shared() {
+        assert_eq!(
+            "most-frequent".parse::<SharedColor>().unwrap(),
+            SharedColor::MostFrequent
+        );
+        assert_eq!(
+            " #f0f ".parse::<SharedColor>().unwrap(),
+            SharedColor::Color(Color::FILLER)
+        );
+        assert!("#1".parse::<SharedColor>().is_err());
+    }
+}
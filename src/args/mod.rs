@@ -1,10 +1,13 @@
 mod slices;
 pub use slices::{parse_slices, Slice};
+mod color;
 pub mod palette;
+pub use color::{SharedColor, SpriteColor};
 
 use std::ffi::OsStr;
-use std::fs::{self, File};
+use std::fs;
 use std::io;
+use std::path::PathBuf;
 
 #[cfg(unix)]
 fn has_leading_at(arg: &OsStr) -> Option<&OsStr> {
@@ -14,7 +17,7 @@ fn has_leading_at(arg: &OsStr) -> Option<&OsStr> {
     debug_assert_eq!('@'.len_utf8(), 1);
     // The argument begins with a '@' if...
     bytes
-        .get(0) // ...there is a first byte...
+        .first() // ...there is a first byte...
         .filter(|c| {
             let mut expected = [0]; // Length checked by debug assertion above
             '@'.encode_utf8(&mut expected);
@@ -42,10 +45,10 @@ fn has_leading_at(arg: &OsStr) -> Option<OsString> {
         .map(|_| OsString::from_wide(&units.collect::<Vec<u16>>()))
 }
 
-/// If the `OsStr` begins with an `@`, treat the rest as a path, and try opening that file.
+/// If the `OsStr` begins with an `@`, treat the rest as a path, and return it.
 /// Otherwise, return `None`.
-pub fn process_leading_at(arg: &OsStr) -> Option<io::Result<File>> {
-    has_leading_at(arg).map(File::open)
+pub fn leading_at_path(arg: &OsStr) -> Option<PathBuf> {
+    has_leading_at(arg).map(PathBuf::from)
 }
 
 pub fn read_leading_at(arg: &OsStr) -> Option<io::Result<Vec<u8>>> {
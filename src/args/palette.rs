@@ -2,3 +2,5 @@ mod parse;
 pub use parse::*;
 mod read;
 pub use read::*;
+mod write;
+pub use write::*;
@@ -1,19 +1,286 @@
+use crate::img::Color;
 use crate::tile::Palettes;
+use arrayvec::ArrayVec;
+use std::convert::TryFrom;
 use std::error;
 use std::fmt::{self, Display, Formatter};
-
 use std::io::{self};
+use std::iter::Peekable;
 
 /// Parses a textual palette spec
+///
+/// Palettes are separated by semicolons or newlines, and the colors within a palette are separated
+/// by commas and/or whitespace. Colors can be written as `#RRGGBB`, `#RGB`, `$xxxx` (RGB555),
+/// or `rgb(r, g, b)` (decimal). A `#` followed by whitespace begins a comment, which lasts until
+/// the end of the line.
 pub fn parse<I: Iterator<Item = char>>(string: I) -> Result<Palettes, ParseError> {
-    let pal = Palettes::new();
-    unimplemented!()
+    let mut chars = Chars::new(string);
+    let mut pal = Palettes::new();
+    let mut colors = ArrayVec::<[Color; 4]>::new();
+    // Position of the current palette's first color, for error reporting
+    let mut start = chars.pos();
+
+    loop {
+        skip_whitespace(&mut chars);
+        let pos = chars.pos();
+
+        let color = match chars.peek() {
+            None => break,
+            Some(';') | Some('\n') => {
+                chars.next();
+                end_palette(&mut pal, &mut colors, start)?;
+                None
+            }
+            Some('#') => {
+                chars.next();
+                match chars.peek() {
+                    // A comment: skip until EOL, but let the newline end the palette
+                    Some(c) if c.is_whitespace() => {
+                        while !matches!(chars.peek(), Some('\n') | None) {
+                            chars.next();
+                        }
+                        None
+                    }
+                    None => None,
+                    Some(c) if c.is_ascii_hexdigit() => Some(parse_hex(&mut chars, pos)?),
+                    Some(c) => return Err(ParseError::IllegalChar(chars.pos(), c)),
+                }
+            }
+            Some('$') => {
+                chars.next();
+                Some(parse_rgb555(&mut chars, pos)?)
+            }
+            Some('r') | Some('R') => Some(parse_rgb(&mut chars)?),
+            Some(c) => return Err(ParseError::IllegalChar(pos, c)),
+        };
+
+        if let Some(color) = color {
+            if colors.is_empty() {
+                start = pos;
+            }
+            colors
+                .try_push(color)
+                .map_err(|_| ParseError::TooManyColors(pos))?;
+
+            // A color must be followed by a delimiter
+            match chars.peek() {
+                Some(',') | Some(';') | None => (),
+                Some(c) if c.is_whitespace() => (),
+                Some(c) => return Err(ParseError::IllegalChar(chars.pos(), c)),
+            }
+            // At most one comma may separate two colors
+            skip_whitespace(&mut chars);
+            if chars.peek() == Some(',') {
+                chars.next();
+            }
+        }
+    }
+    end_palette(&mut pal, &mut colors, start)?;
+
+    if pal.is_empty() {
+        Err(ParseError::Empty)
+    } else {
+        Ok(pal)
+    }
+}
+
+/// Commits the colors read so far as a palette, if there are any.
+fn end_palette(
+    pal: &mut Palettes,
+    colors: &mut ArrayVec<[Color; 4]>,
+    start: Position,
+) -> Result<(), ParseError> {
+    if !colors.is_empty() {
+        pal.push_palette(colors)
+            .map_err(|_| ParseError::TooManyPalettes(start))?;
+        colors.clear();
+    }
+    Ok(())
+}
+
+/// Skips any whitespace, except newlines, which are palette separators.
+fn skip_whitespace<I: Iterator<Item = char>>(chars: &mut Chars<I>) {
+    while matches!(chars.peek(), Some(c) if c.is_whitespace() && c != '\n') {
+        chars.next();
+    }
+}
+
+/// Reads as many digits as possible, returning their value (saturated) and how many there were.
+fn read_digits<I: Iterator<Item = char>>(chars: &mut Chars<I>, radix: u32) -> (u32, usize) {
+    let (mut value, mut count) = (0u32, 0);
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix)) {
+        chars.next();
+        value = value.saturating_mul(radix).saturating_add(digit);
+        count += 1;
+    }
+    (value, count)
+}
+
+/// Consumes the expected character, or reports what was found instead.
+fn expect<I: Iterator<Item = char>>(
+    chars: &mut Chars<I>,
+    expected: char,
+) -> Result<(), ParseError> {
+    let pos = chars.pos();
+    match chars.next() {
+        Some(c) if c.eq_ignore_ascii_case(&expected) => Ok(()),
+        Some(c) => Err(ParseError::IllegalChar(pos, c)),
+        None => Err(ParseError::UnexpectedEof(pos)),
+    }
+}
+
+/// Parses the digits of a `#RRGGBB` or `#RGB` color; the `#` must have been consumed already.
+fn parse_hex<I: Iterator<Item = char>>(
+    chars: &mut Chars<I>,
+    pos: Position,
+) -> Result<Color, ParseError> {
+    let mut digits = ArrayVec::<[u8; 6]>::new();
+    let mut count = 0;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+        chars.next();
+        // Keep counting past 6 digits, to report the actual length
+        let _ = digits.try_push(u8::try_from(digit).unwrap());
+        count += 1;
+    }
+
+    let component = |hi: u8, lo: u8| hi << 4 | lo;
+    match count {
+        3 => Ok(Color::new(
+            Color::rgb_to_rgba((
+                component(digits[0], digits[0]),
+                component(digits[1], digits[1]),
+                component(digits[2], digits[2]),
+            )),
+            None,
+        )),
+        6 => Ok(Color::new(
+            Color::rgb_to_rgba((
+                component(digits[0], digits[1]),
+                component(digits[2], digits[3]),
+                component(digits[4], digits[5]),
+            )),
+            None,
+        )),
+        _ => Err(ParseError::BadHexLength(pos, count)),
+    }
+}
+
+/// Parses the digits of a `$xxxx` color; the `$` must have been consumed already.
+fn parse_rgb555<I: Iterator<Item = char>>(
+    chars: &mut Chars<I>,
+    pos: Position,
+) -> Result<Color, ParseError> {
+    let digits_pos = chars.pos();
+    match read_digits(chars, 16) {
+        (_, 0) => Err(match chars.peek() {
+            Some(c) => ParseError::IllegalChar(digits_pos, c),
+            None => ParseError::UnexpectedEof(digits_pos),
+        }),
+        (value, _) => u16::try_from(value)
+            .ok()
+            .filter(|&value| value <= 0x7FFF)
+            .map(|value| Color::from_rgb555(value, None))
+            .ok_or(ParseError::OutOfRange(pos, "RGB555 color")),
+    }
+}
+
+/// Parses a `rgb(r, g, b)` color.
+fn parse_rgb<I: Iterator<Item = char>>(chars: &mut Chars<I>) -> Result<Color, ParseError> {
+    for c in "rgb(".chars() {
+        expect(chars, c)?;
+    }
+
+    let mut components = [0; 3];
+    for (i, component) in components.iter_mut().enumerate() {
+        if i != 0 {
+            skip_whitespace(chars);
+            expect(chars, ',')?;
+        }
+        skip_whitespace(chars);
+
+        let pos = chars.pos();
+        *component = match read_digits(chars, 10) {
+            (_, 0) => {
+                return Err(match chars.peek() {
+                    Some(c) => ParseError::IllegalChar(pos, c),
+                    None => ParseError::UnexpectedEof(pos),
+                })
+            }
+            (value, _) => {
+                u8::try_from(value).map_err(|_| ParseError::OutOfRange(pos, "Color component"))?
+            }
+        };
+    }
+    skip_whitespace(chars);
+    expect(chars, ')')?;
+
+    Ok(Color::new(
+        Color::rgb_to_rgba((components[0], components[1], components[2])),
+        None,
+    ))
+}
+
+/// A location within a palette spec, for error reporting. Both are 1-based.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// A `char` iterator that keeps track of the position of the next `char`.
+struct Chars<I: Iterator<Item = char>> {
+    chars: Peekable<I>,
+    pos: Position,
+}
+
+impl<I: Iterator<Item = char>> Chars<I> {
+    fn new(chars: I) -> Self {
+        Self {
+            chars: chars.peekable(),
+            pos: Position { line: 1, column: 1 },
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn pos(&self) -> Position {
+        self.pos
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Chars<I> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
 }
 
 #[derive(Debug)]
 pub enum ParseError {
+    BadHexLength(Position, usize),
+    Empty,
+    IllegalChar(Position, char),
     Io(io::Error),
-    Variant2,
+    OutOfRange(Position, &'static str),
+    TooManyColors(Position),
+    TooManyPalettes(Position),
+    UnexpectedEof(Position),
 }
 
 impl From<io::Error> for ParseError {
@@ -27,8 +294,24 @@ impl Display for ParseError {
         use ParseError::*;
 
         match self {
+            BadHexLength(pos, len) => write!(
+                fmt,
+                "{}: Hex colors must have 3 or 6 digits, not {}",
+                pos, len
+            ),
+            Empty => write!(fmt, "No palettes specified"),
+            IllegalChar(pos, c) => write!(fmt, "{}: Illegal character '{}'", pos, c.escape_debug()),
             Io(err) => err.fmt(fmt),
-            Variant2 => todo!(),
+            OutOfRange(pos, which) => write!(fmt, "{}: {} out of range", pos, which),
+            TooManyColors(pos) => {
+                write!(fmt, "{}: Palettes cannot contain more than 4 colors", pos)
+            }
+            TooManyPalettes(pos) => write!(
+                fmt,
+                "{}: Too many palettes (at most 65536 are supported)",
+                pos
+            ),
+            UnexpectedEof(pos) => write!(fmt, "{}: Unexpected end of input", pos),
         }
     }
 }
@@ -38,8 +321,90 @@ impl error::Error for ParseError {
         use ParseError::*;
 
         match self {
+            BadHexLength(..) => None,
+            Empty => None,
+            IllegalChar(..) => None,
             Io(err) => Some(err),
-            Variant2 => None,
+            OutOfRange(..) => None,
+            TooManyColors(..) | TooManyPalettes(..) => None,
+            UnexpectedEof(..) => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ParseError::*;
+
+    fn rgb(red: u8, green: u8, blue: u8) -> Color {
+        Color::new(Color::rgb_to_rgba((red, green, blue)), None)
+    }
+
+    macro_rules! parse_test {
+        ($name:ident, $input:literal => Ok[ $( [ $( $color:expr ),+ ] ),+ ]) => {
+            #[test]
+            fn $name() {
+                let pal = parse($input.chars()).unwrap();
+                let expected: &[&[Color]] = &[ $( &[ $( $color ),+ ] ),+ ];
+                assert_eq!(pal.nb_palettes(), expected.len());
+                for (i, colors) in expected.iter().enumerate() {
+                    assert_eq!(pal.palette_len(i), colors.len());
+                    assert_eq!(&pal[i][..colors.len()], *colors);
+                }
+            }
+        };
+        ($name:ident, $input:literal => Err($err:pat)) => {
+            #[test]
+            fn $name() {
+                let ret = parse($input.chars());
+                if let Err($err) = ret {
+                } else {
+                    panic!("{:?}", ret);
+                }
+            }
+        };
+    }
+
+    parse_test! {empty, "" => Err(Empty)}
+    parse_test! {just_comment, "# Hello yes I am comment" => Err(Empty)}
+    parse_test! {empty_comments, "#\n#000 #\n#" => Ok[[rgb(0, 0, 0)]]}
+    parse_test! {separators_only, " ;\n; " => Err(Empty)}
+
+    parse_test! {hex6, "#FF8000" => Ok[[rgb(0xFF, 0x80, 0x00)]]}
+    parse_test! {hex3, "#f80" => Ok[[rgb(0xFF, 0x88, 0x00)]]}
+    parse_test! {rgb555, "$7C1F" => Ok[[Color::from_rgb555(0x7C1F, None)]]}
+    parse_test! {rgb_fn, "rgb( 1 ,2,  3 )" => Ok[[rgb(1, 2, 3)]]}
+
+    parse_test! {commas, "#000,#555, #aaa ,#fff" => Ok[[
+        rgb(0, 0, 0), rgb(0x55, 0x55, 0x55), rgb(0xAA, 0xAA, 0xAA), rgb(0xFF, 0xFF, 0xFF)
+    ]]}
+    parse_test! {spaces, "#000 #fff" => Ok[[rgb(0, 0, 0), rgb(0xFF, 0xFF, 0xFF)]]}
+    parse_test! {semicolons, "#000,#fff;#f00" => Ok[[rgb(0, 0, 0), rgb(0xFF, 0xFF, 0xFF)], [rgb(0xFF, 0, 0)]]}
+    parse_test! {newlines, "#000 #fff # Grays\n\n#f00, # Reds\n" => Ok[
+        [rgb(0, 0, 0), rgb(0xFF, 0xFF, 0xFF)], [rgb(0xFF, 0, 0)]
+    ]}
+
+    parse_test! {too_many_colors, "#000,#111,#222,#333;\n#000,#111,#222,#333,#444" => Err(TooManyColors(Position { line: 2, column: 21 }))}
+    parse_test! {hex_len, "#00000" => Err(BadHexLength(Position { line: 1, column: 1 }, 5))}
+    parse_test! {hex_too_long, "#0000000" => Err(BadHexLength(_, 7))}
+    parse_test! {comment_no_space, "#fff #cafe au lait" => Err(BadHexLength(Position { line: 1, column: 6 }, 4))}
+    parse_test! {comment_no_space_word, "#fff #Grays" => Err(IllegalChar(Position { line: 1, column: 7 }, 'G'))}
+    parse_test! {rgb555_range, "$8000" => Err(OutOfRange(..))}
+    parse_test! {rgb555_empty, "$" => Err(UnexpectedEof(Position { line: 1, column: 2 }))}
+    parse_test! {rgb_range, "rgb(0, 256, 0)" => Err(OutOfRange(Position { line: 1, column: 8 }, _))}
+    parse_test! {rgb_unclosed, "rgb(0, 0, 0" => Err(UnexpectedEof(_))}
+    parse_test! {rgb_typo, "rbg(0, 0, 0)" => Err(IllegalChar(Position { line: 1, column: 2 }, 'b'))}
+    parse_test! {no_delimiter, "#000#fff" => Err(IllegalChar(Position { line: 1, column: 5 }, '#'))}
+    parse_test! {double_comma, "#000,,#fff" => Err(IllegalChar(Position { line: 1, column: 6 }, ','))}
+    parse_test! {unk_char, "\n  ù" => Err(IllegalChar(Position { line: 2, column: 3 }, 'ù'))}
+
+    #[test]
+    fn position_display() {
+        let pos = Position {
+            line: 3,
+            column: 14,
+        };
+        assert_eq!(format!("{}", pos), "line 3, column 14");
+    }
+}
@@ -1,67 +1,254 @@
-use crate::img::Color;
+use crate::img::{Color, Format, ImageReader, PngReadError, PngReader};
 use crate::tile::Palettes;
-use png::{Decoder, DecodingError};
+use png::DecodingError;
 use std::error;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
-use std::io::{self, BufReader, Read};
+use std::io::{self, Read};
+use std::num::NonZeroU8;
+use std::path::Path;
 
 /// Reads a palette spec from the file it's given.
-/// Either a PNG file (as identified by the first 8 magic bytes), or a raw RGB555 palette file.
-pub fn read(file: File) -> Result<Palettes, ReadError> {
-    let mut file = BufReader::new(file);
-
-    // Read the first 8 bytes, and see if they match the PNG magic bytes
-    let mut first8 = [0; 8];
-    let result = file.read_exact(&mut first8);
-    let mut data = first8.chain(file);
-    // http://www.libpng.org/pub/png/spec/iso/index-object.html#5PNG-file-signature
-    static PNG_MAGIC: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
-
-    match result {
-        Ok(()) if first8 == PNG_MAGIC => {
-            // Magic bytes matched!
-            let pal = Palettes::new();
-            let png = Decoder::new(data);
-
-            todo!()
-        }
+/// The format is detected from the file's first bytes, or failing that, from its extension:
+/// - PNG images (see `read_png`);
+/// - JASC-PAL (`JASC-PAL` header), as written by e.g. Aseprite and Paint Shop Pro;
+/// - GIMP palettes (`GIMP Palette` header, usually `.gpl`);
+/// - Adobe Color Tables (`.act`);
+/// - lospec hex palettes (`.hex`), one `RRGGBB` color per line;
+/// - Otherwise, raw RGBA8888 colors.
+///
+/// Except for PNG swatches, colors are read in order, every 4 of them forming a palette.
+pub fn read(
+    mut file: File,
+    path: &Path,
+    swatch_size: Option<NonZeroU8>,
+) -> Result<Palettes, ReadError> {
+    // Palette files are small, so just read them whole
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
 
-        // Early EOF may just be a small palette file
-        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => {
-            // I/O error
-            Err(err.into())
+    let pal = if Format::detect(&data) == Some(Format::Png) {
+        read_png(&data, swatch_size)?
+    } else if data.starts_with(b"JASC-PAL") {
+        read_jasc(&String::from_utf8_lossy(&data))?
+    } else if data.starts_with(b"GIMP Palette") {
+        read_gpl(&String::from_utf8_lossy(&data))?
+    } else {
+        match extension.as_deref() {
+            // The header is mandatory
+            Some("gpl") => return Err(ReadError::BadGpl(1)),
+            Some("act") => read_act(&data)?,
+            Some("hex") => read_hex(&String::from_utf8_lossy(&data))?,
+            _ => read_raw(&data)?,
         }
+    };
+
+    if pal.is_empty() {
+        Err(ReadError::Empty)
+    } else {
+        Ok(pal)
+    }
+}
+
+/// Reads a PNG palette from its PLTE chunk if it has one, every 4 entries forming a palette;
+/// otherwise, or if a swatch size is given, each row of swatches is one palette (see `read_swatches`).
+fn read_png(data: &[u8], swatch_size: Option<NonZeroU8>) -> Result<Palettes, ReadError> {
+    let mut png = PngReader::new(data)?;
 
-        Ok(()) | Err(_) => {
-            // Raw RGBA8888 colors
+    match (swatch_size, png.palette()) {
+        (None, Some(colors)) => {
             let mut pal = Palettes::new();
-            let mut color = [0; 4];
-
-            loop {
-                match data.read_exact(&mut color) {
-                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
-                    Err(err) => return Err(err.into()),
-                    Ok(()) => {
-                        if pal
-                            .push(Color::new((color[0], color[1], color[2], color[3]), None))
-                            .is_err()
-                        {
-                            return Err(ReadError::TooManyColors);
-                        }
-                    }
-                }
+            // Colors keep their PLTE index, so that they match the same indexes in the image
+            for palette in colors.chunks(4) {
+                pal.push_palette(palette)
+                    .map_err(|_| ReadError::TooManyColors)?;
             }
             Ok(pal)
         }
+        (size, _) => read_swatches(&mut png, size.map_or(1, NonZeroU8::get)),
+    }
+}
+
+/// Appends colors to a new palette set, 4 per palette.
+fn push_all<I: Iterator<Item = Color>>(colors: I) -> Result<Palettes, ReadError> {
+    let mut pal = Palettes::new();
+    for color in colors {
+        pal.push(color).map_err(|_| ReadError::TooManyColors)?;
+    }
+    Ok(pal)
+}
+
+/// Reads raw RGBA8888 colors; any excess bytes at the end are ignored.
+fn read_raw(data: &[u8]) -> Result<Palettes, ReadError> {
+    push_all(
+        data.chunks_exact(4)
+            .map(|color| Color::new((color[0], color[1], color[2], color[3]), None)),
+    )
+}
+
+/// Parses the first three whitespace-separated decimal numbers of a line as a RGB888 color.
+/// Anything after them is ignored.
+fn parse_decimal_rgb(line: &str) -> Option<Color> {
+    let mut components = line.split_whitespace().map(str::parse::<u8>);
+    let mut next = || components.next().and_then(Result::ok);
+    Some(Color::new(
+        Color::rgb_to_rgba((next()?, next()?, next()?)),
+        None,
+    ))
+}
+
+/// Iterates on a text file's lines, trimmed and numbered (1-based, for error reporting).
+fn numbered_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+}
+
+/// Reads a JASC-PAL palette: a `JASC-PAL` line, a `0100` version line, the amount of colors,
+/// and then one `r g b` line per color.
+fn read_jasc(text: &str) -> Result<Palettes, ReadError> {
+    let mut lines = numbered_lines(text);
+
+    for (n, expected) in [(1, "JASC-PAL"), (2, "0100")].iter() {
+        match lines.next() {
+            Some((_, line)) if line == *expected => (),
+            _ => return Err(ReadError::BadJasc(*n)),
+        }
+    }
+    let nb_colors = match lines.next() {
+        Some((_, line)) => line.parse().map_err(|_| ReadError::BadJasc(3))?,
+        None => return Err(ReadError::BadJasc(3)),
+    };
+
+    let colors = lines
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| parse_decimal_rgb(line).ok_or(ReadError::BadJasc(n)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if colors.len() != nb_colors {
+        return Err(ReadError::JascCountMismatch(nb_colors, colors.len()));
+    }
+    push_all(colors.into_iter())
+}
+
+/// Reads a GIMP palette: a `GIMP Palette` line, then `r g b [name]` lines.
+/// Comments, empty lines, and the `Name:` and `Columns:` attributes are ignored.
+fn read_gpl(text: &str) -> Result<Palettes, ReadError> {
+    let colors = numbered_lines(text)
+        .skip(1) // The header has already been checked
+        .filter(|(_, line)| {
+            !line.is_empty()
+                && !line.starts_with('#')
+                && !line.starts_with("Name:")
+                && !line.starts_with("Columns:")
+        })
+        .map(|(n, line)| parse_decimal_rgb(line).ok_or(ReadError::BadGpl(n)))
+        .collect::<Result<Vec<_>, _>>()?;
+    push_all(colors.into_iter())
+}
+
+/// Reads an Adobe Color Table: 256 RGB888 colors, optionally followed by the amount of colors
+/// actually used and the index of the transparent one (both 16-bit big-endian, the latter being
+/// $FFFF if there is none).
+fn read_act(data: &[u8]) -> Result<Palettes, ReadError> {
+    let (nb_colors, transparent) = match data.len() {
+        768 => (256, None),
+        772 => {
+            let nb_colors = u16::from_be_bytes([data[768], data[769]]);
+            let transparent = u16::from_be_bytes([data[770], data[771]]);
+            if nb_colors > 256 {
+                return Err(ReadError::BadActCount(nb_colors));
+            }
+            (
+                usize::from(nb_colors),
+                Some(usize::from(transparent)).filter(|&index| index != 0xFFFF),
+            )
+        }
+        len => return Err(ReadError::BadActLength(len)),
+    };
+
+    push_all(
+        data[..768]
+            .chunks_exact(3)
+            .take(nb_colors)
+            .enumerate()
+            .map(|(i, rgb)| {
+                let alpha = if transparent == Some(i) { 0 } else { 255 };
+                Color::new((rgb[0], rgb[1], rgb[2], alpha), None)
+            }),
+    )
+}
+
+/// Reads a lospec hex palette: one `RRGGBB` color per line (a leading `#` is tolerated).
+fn read_hex(text: &str) -> Result<Palettes, ReadError> {
+    let colors = numbered_lines(text)
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| {
+            let digits = line.strip_prefix('#').unwrap_or(line);
+            if digits.len() != 6 {
+                return Err(ReadError::BadHex(n));
+            }
+            let component = |i: usize| {
+                digits
+                    .get(i..i + 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(ReadError::BadHex(n))
+            };
+            Ok(Color::new(
+                Color::rgb_to_rgba((component(0)?, component(2)?, component(4)?)),
+                None,
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    push_all(colors.into_iter())
+}
+
+/// Reads a palette "swatch" image: each swatch is a square of `size` pixels, whose top-left pixel
+/// gives its color, and each row of swatches (up to 4) is a palette.
+fn read_swatches<R: Read>(png: &mut PngReader<R>, size: u8) -> Result<Palettes, ReadError> {
+    let img = png.read_image()?;
+    let size = u32::from(size);
+
+    if img.width() % size != 0 || img.height() % size != 0 {
+        return Err(ReadError::SwatchMismatch(img.width(), img.height(), size));
+    }
+    let nb_swatches = img.width() / size;
+    if nb_swatches > 4 {
+        return Err(ReadError::TooManySwatches(nb_swatches));
     }
+
+    let mut pal = Palettes::new();
+    for y in (0..img.height()).step_by(size as usize) {
+        let colors: Vec<_> = (0..nb_swatches)
+            .map(|x| {
+                // The swatch image's indexes are irrelevant to the image being converted
+                let [r, g, b, a] = img[(x * size, y)].rgba();
+                Color::new((r, g, b, a), None)
+            })
+            .collect();
+        pal.push_palette(&colors)
+            .map_err(|_| ReadError::TooManyColors)?;
+    }
+    Ok(pal)
 }
 
 #[derive(Debug)]
 pub enum ReadError {
+    BadActCount(u16),
+    BadActLength(usize),
+    BadGpl(usize),  // Line
+    BadHex(usize),  // Line
+    BadJasc(usize), // Line
     BadPng(DecodingError),
+    Empty,
     Io(io::Error),
+    JascCountMismatch(usize, usize), // Declared, actual
+    PngReading(PngReadError),
+    SwatchMismatch(u32, u32, u32), // width, height, swatch size
     TooManyColors,
+    TooManySwatches(u32),
 }
 
 impl From<DecodingError> for ReadError {
@@ -70,6 +257,12 @@ impl From<DecodingError> for ReadError {
     }
 }
 
+impl From<PngReadError> for ReadError {
+    fn from(err: PngReadError) -> Self {
+        Self::PngReading(err)
+    }
+}
+
 impl From<io::Error> for ReadError {
     fn from(err: io::Error) -> Self {
         Self::Io(err)
@@ -81,13 +274,43 @@ impl Display for ReadError {
         use ReadError::*;
 
         match self {
+            BadActCount(nb) => write!(
+                fmt,
+                "ACT palette declares {} colors, but can contain at most 256",
+                nb
+            ),
+            BadActLength(len) => write!(
+                fmt,
+                "ACT palettes must be 768 or 772 bytes long, not {}",
+                len
+            ),
+            BadGpl(line) => write!(fmt, "Invalid GIMP palette, at line {}", line),
+            BadHex(line) => write!(fmt, "Invalid hex palette, at line {}", line),
+            BadJasc(line) => write!(fmt, "Invalid JASC-PAL palette, at line {}", line),
             BadPng(err) => write!(
                 fmt,
                 "PNG file detected, but got an error while decoding: {}",
                 err
             ),
+            Empty => write!(fmt, "Palette file contains no colors"),
             Io(err) => err.fmt(fmt),
-            TooManyColors => write!(fmt, ""),
+            JascCountMismatch(declared, actual) => write!(
+                fmt,
+                "JASC-PAL palette declares {} colors, but contains {}",
+                declared, actual
+            ),
+            PngReading(err) => write!(fmt, "Error reading PNG palette: {}", err),
+            SwatchMismatch(width, height, size) => write!(
+                fmt,
+                "Palette image ({}x{} px) cannot be divided into {}x{} px swatches",
+                width, height, size, size
+            ),
+            TooManyColors => write!(fmt, "Too many palettes (at most 65536 are supported)"),
+            TooManySwatches(nb) => write!(
+                fmt,
+                "Palette image has {} swatches per row, but palettes contain at most 4 colors",
+                nb
+            ),
         }
     }
 }
@@ -97,9 +320,104 @@ impl error::Error for ReadError {
         use ReadError::*;
 
         match self {
+            BadActCount(..) | BadActLength(..) => None,
+            BadGpl(..) | BadHex(..) | BadJasc(..) => None,
             BadPng(err) => Some(err),
+            Empty => None,
             Io(err) => Some(err),
-            TooManyColors => None,
+            JascCountMismatch(..) => None,
+            PngReading(err) => Some(err),
+            SwatchMismatch(..) => None,
+            TooManyColors | TooManySwatches(..) => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(red: u8, green: u8, blue: u8) -> Color {
+        Color::new(Color::rgb_to_rgba((red, green, blue)), None)
+    }
+
+    #[test]
+    fn jasc() {
+        let pal = read_jasc(
+            "JASC-PAL\r\n0100\r\n5\r\n255 255 255\r\n0 0 0\r\n1 2 3\r\n4 5 6\r\n7 8 9\r\n",
+        )
+        .unwrap();
+        assert_eq!(pal.nb_palettes(), 2);
+        assert_eq!(pal[0][2], rgb(1, 2, 3));
+        assert_eq!(pal.palette_len(1), 1);
+        assert_eq!(pal[1][0], rgb(7, 8, 9));
+    }
+
+    #[test]
+    fn jasc_errors() {
+        assert!(matches!(
+            read_jasc("JASC-PAL\n0200\n1\n0 0 0\n"),
+            Err(ReadError::BadJasc(2))
+        ));
+        assert!(matches!(
+            read_jasc("JASC-PAL\n0100\n1\n0 0\n"),
+            Err(ReadError::BadJasc(4))
+        ));
+        assert!(matches!(
+            read_jasc("JASC-PAL\n0100\n2\n0 0 0\n"),
+            Err(ReadError::JascCountMismatch(2, 1))
+        ));
+    }
+
+    #[test]
+    fn gpl() {
+        let pal = read_gpl(
+            "GIMP Palette\nName: Test\nColumns: 4\n# Comment\n\n 12  34  56\tSome color\n255 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(pal.palette_len(0), 2);
+        assert_eq!(pal[0][..2], [rgb(12, 34, 56), rgb(255, 0, 0)]);
+        assert!(matches!(
+            read_gpl("GIMP Palette\n256 0 0\n"),
+            Err(ReadError::BadGpl(2))
+        ));
+    }
+
+    #[test]
+    fn act() {
+        let mut data = vec![0; 772];
+        data[3..6].copy_from_slice(&[1, 2, 3]);
+        data[768..772].copy_from_slice(&[0, 2, 0, 0]); // 2 colors, the first being transparent
+        let pal = read_act(&data).unwrap();
+        assert_eq!(pal.palette_len(0), 2);
+        assert_eq!(pal[0][..2], [Color::new((0, 0, 0, 0), None), rgb(1, 2, 3)]);
+
+        assert_eq!(read_act(&data[..768]).unwrap().nb_palettes(), 64);
+        assert!(matches!(
+            read_act(&data[..700]),
+            Err(ReadError::BadActLength(700))
+        ));
+    }
+
+    #[test]
+    fn raw_limit() {
+        // As many palettes as the palette map can refer to
+        let data = vec![0; 65536 * 16];
+        assert_eq!(read_raw(&data).unwrap().nb_palettes(), 65536);
+        assert!(matches!(
+            read_raw(&vec![0; 65536 * 16 + 4]),
+            Err(ReadError::TooManyColors)
+        ));
+    }
+
+    #[test]
+    fn hex() {
+        let pal = read_hex("ff8000\n#0000FF\r\n\n").unwrap();
+        assert_eq!(pal[0][..2], [rgb(0xFF, 0x80, 0x00), rgb(0, 0, 0xFF)]);
+        assert!(matches!(read_hex("ff800\n"), Err(ReadError::BadHex(1))));
+        assert!(matches!(
+            read_hex("000000\nff80zz\n"),
+            Err(ReadError::BadHex(2))
+        ));
+    }
+}
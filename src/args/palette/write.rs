@@ -0,0 +1,219 @@
+use crate::img::{Color, Rounding};
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// The formats palettes can be written in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PaletteFormat {
+    /// The GBC's native format: little-endian RGB555
+    Rgb555,
+    /// Raw RGBA8888
+    Rgba8888,
+    /// JASC-PAL, as read by e.g. Aseprite and Paint Shop Pro
+    Jasc,
+    /// GIMP palette
+    Gpl,
+    /// Adobe Color Table, as read by Photoshop
+    Act,
+    /// RGBASM source, one `dw` directive per palette
+    Asm,
+}
+
+impl FromStr for PaletteFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        use PaletteFormat::*;
+
+        match name {
+            "rgb555" => Ok(Rgb555),
+            "rgba8888" => Ok(Rgba8888),
+            "jasc" => Ok(Jasc),
+            "gpl" => Ok(Gpl),
+            "act" => Ok(Act),
+            "asm" => Ok(Asm),
+            _ => Err(UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownFormat(String);
+
+impl Display for UnknownFormat {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Unknown palette format \"{}\"", self.0)
+    }
+}
+
+impl error::Error for UnknownFormat {}
+
+/// Writes palettes in the given format; `rounding` is only used by RGB555-based formats.
+pub fn write<W: Write>(
+    output: &mut W,
+    palettes: &[[Color; 4]],
+    format: PaletteFormat,
+    rounding: Rounding,
+) -> io::Result<()> {
+    let colors = || palettes.iter().flat_map(|palette| palette.iter());
+    let nb_colors = palettes.len() * 4;
+
+    match format {
+        PaletteFormat::Rgb555 => {
+            for color in colors() {
+                output.write_all(&color.to_rgb555(rounding).to_le_bytes())?;
+            }
+        }
+        PaletteFormat::Rgba8888 => {
+            for color in colors() {
+                output.write_all(&color.rgba())?;
+            }
+        }
+        PaletteFormat::Jasc => {
+            // JASC-PAL files conventionally use CRLF line endings
+            write!(output, "JASC-PAL\r\n0100\r\n{}\r\n", nb_colors)?;
+            for color in colors() {
+                let [r, g, b, _] = color.rgba();
+                write!(output, "{} {} {}\r\n", r, g, b)?;
+            }
+        }
+        PaletteFormat::Gpl => {
+            writeln!(output, "GIMP Palette\nName: rsgbgfx\nColumns: 4\n#")?;
+            for (i, palette) in palettes.iter().enumerate() {
+                for (j, color) in palette.iter().enumerate() {
+                    let [r, g, b, _] = color.rgba();
+                    writeln!(output, "{:3} {:3} {:3}\tPalette {} #{}", r, g, b, i, j)?;
+                }
+            }
+        }
+        PaletteFormat::Act => {
+            // ACT files always contain 256 colors, followed by how many are actually used
+            let count = u16::try_from(nb_colors)
+                .ok()
+                .filter(|&count| count <= 256)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "ACT palettes can contain at most 64 palettes, not {}",
+                            palettes.len()
+                        ),
+                    )
+                })?;
+            for color in colors() {
+                let [r, g, b, _] = color.rgba();
+                output.write_all(&[r, g, b])?;
+            }
+            output.write_all(&vec![0; (256 - nb_colors) * 3])?;
+            output.write_all(&count.to_be_bytes())?;
+            output.write_all(&[0xFF, 0xFF])?; // No transparent color
+        }
+        PaletteFormat::Asm => {
+            for (i, palette) in palettes.iter().enumerate() {
+                writeln!(
+                    output,
+                    "\tdw ${:04X}, ${:04X}, ${:04X}, ${:04X} ; Palette {}",
+                    palette[0].to_rgb555(rounding),
+                    palette[1].to_rgb555(rounding),
+                    palette[2].to_rgb555(rounding),
+                    palette[3].to_rgb555(rounding),
+                    i
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_str(palettes: &[[Color; 4]], format: PaletteFormat) -> String {
+        let mut output = Vec::new();
+        write(&mut output, palettes, format, Rounding::default()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn palette() -> [Color; 4] {
+        [
+            Color::new((255, 255, 255, 255), None),
+            Color::new((1, 2, 3, 255), None),
+            Color::FILLER,
+            Color::default(),
+        ]
+    }
+
+    #[test]
+    fn jasc() {
+        assert_eq!(
+            write_str(&[palette()], PaletteFormat::Jasc),
+            "JASC-PAL\r\n0100\r\n4\r\n255 255 255\r\n1 2 3\r\n255 0 255\r\n0 0 0\r\n"
+        );
+    }
+
+    #[test]
+    fn gpl() {
+        assert!(write_str(&[palette()], PaletteFormat::Gpl).ends_with(
+            "\n  1   2   3\tPalette 0 #1\n255   0 255\tPalette 0 #2\n  0   0   0\tPalette 0 #3\n"
+        ));
+    }
+
+    #[test]
+    fn act() {
+        let mut output = Vec::new();
+        write(
+            &mut output,
+            &[palette()],
+            PaletteFormat::Act,
+            Rounding::default(),
+        )
+        .unwrap();
+        assert_eq!(output.len(), 772);
+        assert_eq!(output[3..6], [1, 2, 3]);
+        assert_eq!(output[768..], [0, 4, 0xFF, 0xFF]);
+
+        let too_many = vec![palette(); 65];
+        assert!(write(
+            &mut Vec::new(),
+            &too_many,
+            PaletteFormat::Act,
+            Rounding::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn asm() {
+        assert_eq!(
+            write_str(&[palette()], PaletteFormat::Asm),
+            "\tdw $7FFF, $0000, $7C1F, $0000 ; Palette 0\n"
+        );
+    }
+
+    #[test]
+    fn rgb555() {
+        let mut palette = palette();
+        palette[1] = Color::new((0x87, 0x80, 0x7F, 255), None);
+        let mut output = Vec::new();
+        write(
+            &mut output,
+            &[palette],
+            PaletteFormat::Rgb555,
+            Rounding::Scale,
+        )
+        .unwrap();
+        assert_eq!(output, [0xFF, 0x7F, 0xF0, 0x3D, 0x1F, 0x7C, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn names() {
+        for name in &["rgb555", "rgba8888", "jasc", "gpl", "act", "asm"] {
+            assert!(name.parse::<PaletteFormat>().is_ok());
+        }
+        assert!("png".parse::<PaletteFormat>().is_err());
+    }
+}
@@ -2,7 +2,7 @@ use crate::util::{CharReader, CharReaderError};
 use std::convert::TryFrom;
 use std::error;
 use std::fmt::{self, Display, Formatter};
-use std::io::{self, Read};
+use std::io::{self, BufReader, Read};
 use std::iter::Peekable;
 
 // Everything's public because it's plain ol' data
@@ -32,7 +32,7 @@ pub fn parse_slices<T: Read>(
     block_height: u8,
 ) -> Result<(Vec<Slice>, usize), ParseError> {
     let (mut slices, mut nb_blocks) = (vec![], 0);
-    let mut chars = CharReader::new(input.bytes()).peekable();
+    let mut chars = CharReader::new(BufReader::new(input).bytes()).peekable();
 
     skip_whitespace(&mut chars, true)?; // Skip initial whitespace
     loop {
@@ -0,0 +1,1007 @@
+use super::{Color, Image, ImageReader, MAX_PIXELS};
+use crate::args::Slice;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Cursor, Read};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+// Chunk types
+const OLD_PALETTE: u16 = 0x0004;
+const LAYER: u16 = 0x2004;
+const CEL: u16 = 0x2005;
+const PALETTE: u16 = 0x2019;
+const SLICE: u16 = 0x2022;
+
+// Layer flags
+const VISIBLE: u16 = 1;
+const BACKGROUND: u16 = 8;
+const REFERENCE: u16 = 64;
+
+/// Which frames of an Aseprite file to read
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AseFrames {
+    One(u16),
+    /// All frames, side by side
+    All,
+}
+
+impl FromStr for AseFrames {
+    type Err = InvalidFrame;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "all" => Ok(AseFrames::All),
+            _ => string
+                .parse()
+                .map(AseFrames::One)
+                .map_err(|_| InvalidFrame(string.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidFrame(String);
+
+impl Display for InvalidFrame {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "Invalid frame \"{}\" (expected a number or \"all\")",
+            self.0
+        )
+    }
+}
+
+impl error::Error for InvalidFrame {}
+
+/// How to turn an Aseprite file into a single image
+#[derive(Debug, Clone)]
+pub struct AseOptions {
+    pub layer: Option<String>, // If `None`, all visible layers are used
+    pub frames: AseFrames,
+    pub slices: bool, // Also read the file's slices
+}
+
+impl Default for AseOptions {
+    fn default() -> Self {
+        Self {
+            layer: None,
+            frames: AseFrames::One(0),
+            slices: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Layer {
+    flags: u16,
+    is_group: bool,
+    parent: Option<usize>,
+    opacity: u8,
+    name: String,
+}
+
+#[derive(Debug)]
+struct Cel {
+    layer: usize,
+    x: i16,
+    y: i16,
+    opacity: u8,
+    z_index: i16,
+    contents: CelContents,
+}
+
+#[derive(Debug)]
+enum CelContents {
+    Image {
+        width: u16,
+        height: u16,
+        pixels: Vec<u8>,
+    },
+    Linked(u16),      // Frame whose cel on the same layer is to be used
+    Unsupported(u16), // Cel type, e.g. tilemaps; only an error if the cel is drawn
+}
+
+#[derive(Debug)]
+struct SliceKey {
+    frame: u32,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+}
+
+pub struct AseReader<R: Read> {
+    input: PhantomData<R>, // The whole file is parsed up-front, since frames hold the palette
+    width: u16,
+    height: u16,
+    depth: u16, // Bits per pixel
+    transparent: u8,
+    layers: Vec<Layer>,
+    frames: Vec<Vec<Cel>>,
+    palette: Vec<Color>,
+    slices: Vec<(String, Vec<SliceKey>)>,
+    options: AseOptions,
+}
+
+/// A little-endian reader over a byte slice
+struct Bytes<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AseError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(AseError::Corrupt("unexpected end of data"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, AseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, AseError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i16(&mut self) -> Result<i16, AseError> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> Result<u32, AseError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, AseError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> Result<String, AseError> {
+        let len = self.u16()?;
+        Ok(String::from_utf8_lossy(self.take(usize::from(len))?).into_owned())
+    }
+}
+
+impl<R: Read> ImageReader<R> for AseReader<R> {
+    type NewError = AseError;
+
+    fn new(mut input: R) -> Result<Self, Self::NewError> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut header = Bytes::new(&data);
+        header.u32()?; // File size
+        if header.u16()? != 0xA5E0 {
+            return Err(AseError::NotAse);
+        }
+        let nb_frames = header.u16()?;
+        let (width, height, depth) = (header.u16()?, header.u16()?, header.u16()?);
+        if depth != 32 && depth != 16 && depth != 8 {
+            return Err(AseError::UnsupportedDepth(depth));
+        }
+        header.take(14)?; // Flags, speed, and two reserved fields
+        let transparent = header.u8()?;
+
+        let mut reader = Self {
+            input: PhantomData,
+            width,
+            height,
+            depth,
+            transparent,
+            layers: Vec::new(),
+            frames: Vec::with_capacity(nb_frames.into()),
+            palette: Vec::new(),
+            slices: Vec::new(),
+            options: AseOptions::default(),
+        };
+        let mut has_new_palette = false;
+        let mut levels: Vec<(u16, usize)> = Vec::new(); // Child level and index of group layers
+
+        let mut pos = 128; // Frames start right after the header
+        for _ in 0..nb_frames {
+            let mut frame = Bytes::new(data.get(pos..).unwrap_or(&[]));
+            let frame_size = frame.u32()?;
+            if frame.u16()? != 0xF1FA {
+                return Err(AseError::Corrupt("bad frame magic number"));
+            }
+            // The size includes the header, so anything less would read the same frame again
+            if frame_size < 16 {
+                return Err(AseError::Corrupt("frame too small"));
+            }
+            let old_nb_chunks = frame.u16()?;
+            frame.take(4)?; // Duration, reserved
+            let nb_chunks = match frame.u32()? {
+                0 => u32::from(old_nb_chunks),
+                nb_chunks => nb_chunks,
+            };
+
+            let mut cels = Vec::new();
+            for _ in 0..nb_chunks {
+                let chunk_size = frame.u32()?;
+                let kind = frame.u16()?;
+                let body = frame.take(
+                    usize::try_from(chunk_size)
+                        .unwrap()
+                        .checked_sub(6)
+                        .ok_or(AseError::Corrupt("chunk too small"))?,
+                )?;
+                let mut chunk = Bytes::new(body);
+
+                match kind {
+                    LAYER => {
+                        let flags = chunk.u16()?;
+                        let layer_kind = chunk.u16()?;
+                        let level = chunk.u16()?;
+                        chunk.take(6)?; // Default size, blend mode
+                        let opacity = chunk.u8()?;
+                        chunk.take(3)?;
+                        let name = chunk.string()?;
+
+                        // The parent is the closest preceding group with a lower child level
+                        while levels.last().is_some_and(|&(other, _)| other >= level) {
+                            levels.pop();
+                        }
+                        let index = reader.layers.len();
+                        reader.layers.push(Layer {
+                            flags,
+                            is_group: layer_kind == 1,
+                            parent: levels.last().map(|&(_, parent)| parent),
+                            opacity,
+                            name,
+                        });
+                        if layer_kind == 1 {
+                            levels.push((level, index));
+                        }
+                    }
+                    CEL => {
+                        let layer = usize::from(chunk.u16()?);
+                        let (x, y) = (chunk.i16()?, chunk.i16()?);
+                        let opacity = chunk.u8()?;
+                        let cel_kind = chunk.u16()?;
+                        let z_index = chunk.i16()?;
+                        chunk.take(5)?;
+                        let contents = match cel_kind {
+                            0 | 2 => {
+                                let (width, height) = (chunk.u16()?, chunk.u16()?);
+                                if usize::from(width) * usize::from(height) > MAX_PIXELS {
+                                    return Err(AseError::TooBig(width.into(), height.into()));
+                                }
+                                let expected = usize::from(width)
+                                    * usize::from(height)
+                                    * usize::from(depth / 8);
+                                let rest = &body[chunk.pos..];
+                                let pixels = if cel_kind == 0 {
+                                    rest.to_vec()
+                                } else {
+                                    inflate(rest, expected)?
+                                };
+                                if pixels.len() < expected {
+                                    return Err(AseError::Corrupt("truncated cel"));
+                                }
+                                CelContents::Image {
+                                    width,
+                                    height,
+                                    pixels,
+                                }
+                            }
+                            1 => CelContents::Linked(chunk.u16()?),
+                            _ => CelContents::Unsupported(cel_kind),
+                        };
+                        cels.push(Cel {
+                            layer,
+                            x,
+                            y,
+                            opacity,
+                            z_index,
+                            contents,
+                        });
+                    }
+                    PALETTE => {
+                        has_new_palette = true;
+                        let size = usize::try_from(chunk.u32()?).unwrap();
+                        let (first, last) = (chunk.u32()?, chunk.u32()?);
+                        chunk.take(8)?;
+                        // Indices are only a byte
+                        if size > 256 {
+                            return Err(AseError::Corrupt("palette too big"));
+                        }
+                        reader.palette.resize(size, Color::TRANSPARENT);
+                        for i in first..=last {
+                            let flags = chunk.u16()?;
+                            let rgba = (chunk.u8()?, chunk.u8()?, chunk.u8()?, chunk.u8()?);
+                            if flags & 1 != 0 {
+                                chunk.string()?; // Name
+                            }
+                            let index = u8::try_from(i)
+                                .map_err(|_| AseError::Corrupt("palette too big"))?;
+                            *reader
+                                .palette
+                                .get_mut(usize::from(index))
+                                .ok_or(AseError::Corrupt("palette entry out of range"))? =
+                                Color::new(rgba, Some(index));
+                        }
+                    }
+                    // Only present for backwards compatibility, unless the palette has no alpha
+                    OLD_PALETTE if !has_new_palette => {
+                        let mut index = 0;
+                        for _ in 0..chunk.u16()? {
+                            index += usize::from(chunk.u8()?);
+                            let count = match chunk.u8()? {
+                                0 => 256,
+                                count => usize::from(count),
+                            };
+                            for _ in 0..count {
+                                let rgb = (chunk.u8()?, chunk.u8()?, chunk.u8()?);
+                                let slot = u8::try_from(index)
+                                    .map_err(|_| AseError::Corrupt("palette too big"))?;
+                                if reader.palette.len() <= index {
+                                    reader.palette.resize(index + 1, Color::TRANSPARENT);
+                                }
+                                reader.palette[index] =
+                                    Color::new(Color::rgb_to_rgba(rgb), Some(slot));
+                                index += 1;
+                            }
+                        }
+                    }
+                    SLICE => {
+                        let nb_keys = chunk.u32()?;
+                        let flags = chunk.u32()?;
+                        chunk.u32()?;
+                        let name = chunk.string()?;
+                        let mut keys = Vec::new();
+                        for _ in 0..nb_keys {
+                            keys.push(SliceKey {
+                                frame: chunk.u32()?,
+                                x: chunk.i32()?,
+                                y: chunk.i32()?,
+                                width: chunk.u32()?,
+                                height: chunk.u32()?,
+                            });
+                            // 9-patch center, and pivot
+                            chunk.take(if flags & 1 != 0 { 16 } else { 0 })?;
+                            chunk.take(if flags & 2 != 0 { 8 } else { 0 })?;
+                        }
+                        reader.slices.push((name, keys));
+                    }
+                    _ => (), // Nothing else affects how the image looks
+                }
+            }
+
+            reader.frames.push(cels);
+            pos += usize::try_from(frame_size).unwrap();
+        }
+
+        // In indexed images, the transparent color is... transparent
+        if depth == 8 {
+            if let Some(color) = reader.palette.get_mut(usize::from(transparent)) {
+                let [red, green, blue, _] = color.rgba();
+                *color = Color::new((red, green, blue, 0), Some(transparent));
+            }
+        }
+
+        Ok(reader)
+    }
+
+    type ReadError = AseError;
+
+    fn read_image(&mut self) -> Result<Image, Self::ReadError> {
+        let frames = self.selected_frames()?;
+        let included = self.included_layers()?;
+        let (width, height) = (usize::from(self.width), usize::from(self.height));
+        let stride = width * frames.len();
+        if stride * height > MAX_PIXELS {
+            return Err(AseError::TooBig(
+                u32::try_from(stride).unwrap(),
+                self.height.into(),
+            ));
+        }
+
+        let background = match self.depth {
+            8 => self
+                .palette
+                .get(usize::from(self.transparent))
+                .cloned()
+                .unwrap_or(Color::TRANSPARENT),
+            _ => Color::TRANSPARENT,
+        };
+        let mut pixels = vec![background; stride * height];
+
+        for (k, &frame) in frames.iter().enumerate() {
+            // Cels are drawn by increasing "order", which their z-index offsets from their layer's
+            let mut cels: Vec<_> = self.frames[usize::from(frame)]
+                .iter()
+                .filter(|cel| included.get(cel.layer).copied().unwrap_or(false))
+                .collect();
+            cels.sort_by_key(|cel| (cel.layer as isize + isize::from(cel.z_index), cel.z_index));
+
+            for cel in cels {
+                let (cel_width, cel_height, cel_pixels) = self.cel_image(cel)?;
+                let layer = &self.layers[cel.layer];
+                let opacity = u32::from(cel.opacity) * u32::from(layer.opacity) / 255;
+                let is_background = layer.flags & BACKGROUND != 0;
+
+                for cel_y in 0..usize::from(cel_height) {
+                    let y = isize::from(cel.y) + cel_y as isize;
+                    for cel_x in 0..usize::from(cel_width) {
+                        let x = isize::from(cel.x) + cel_x as isize;
+                        if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+                            continue;
+                        }
+                        let dest = &mut pixels[y as usize * stride + k * width + x as usize];
+                        let i = cel_y * usize::from(cel_width) + cel_x;
+
+                        match self.depth {
+                            8 => {
+                                let index = cel_pixels[i];
+                                if index != self.transparent || is_background {
+                                    *dest = self
+                                        .palette
+                                        .get(usize::from(index))
+                                        .cloned()
+                                        .ok_or(AseError::BadIndex(index))?;
+                                }
+                            }
+                            16 => {
+                                let (value, alpha) = (cel_pixels[i * 2], cel_pixels[i * 2 + 1]);
+                                let rgb = Color::gray_to_rgb(value);
+                                blend(dest, (rgb.0, rgb.1, rgb.2, alpha), opacity);
+                            }
+                            _ => {
+                                let rgba = &cel_pixels[i * 4..i * 4 + 4];
+                                blend(dest, (rgba[0], rgba[1], rgba[2], rgba[3]), opacity);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Image::new(
+            u32::try_from(stride).unwrap(),
+            self.height.into(),
+            pixels,
+        ))
+    }
+}
+
+impl<R: Read> AseReader<R> {
+    pub fn set_options(&mut self, options: &AseOptions) {
+        self.options = options.clone();
+    }
+
+    /// Returns the file's palette, if the image is indexed; each color carries its index.
+    pub fn palette(&self) -> Option<Vec<Color>> {
+        if self.depth == 8 {
+            Some(self.palette.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Converts the file's slices into `Slice`s, as they are on the selected frames.
+    pub fn slices(&self) -> Result<Vec<Slice>, AseError> {
+        let mut slices = Vec::new();
+        for (k, frame) in self.selected_frames()?.into_iter().enumerate() {
+            for (name, keys) in &self.slices {
+                // Each key applies until the next one
+                let key = match keys.iter().rev().find(|key| key.frame <= u32::from(frame)) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if key.x < 0 || key.y < 0 || key.width % 8 != 0 || key.height % 8 != 0 {
+                    return Err(AseError::BadSlice(name.clone()));
+                }
+                slices.push(Slice {
+                    x: key.x as u32 + u32::try_from(k).unwrap() * u32::from(self.width),
+                    y: key.y as u32,
+                    width: key.width / 8,
+                    height: key.height / 8,
+                });
+            }
+        }
+        Ok(slices)
+    }
+
+    fn selected_frames(&self) -> Result<Vec<u16>, AseError> {
+        let nb_frames = u16::try_from(self.frames.len()).unwrap();
+        match self.options.frames {
+            AseFrames::All => Ok((0..nb_frames).collect()),
+            AseFrames::One(frame) if frame < nb_frames => Ok(vec![frame]),
+            AseFrames::One(frame) => Err(AseError::NoFrame(frame, nb_frames)),
+        }
+    }
+
+    /// Computes which layers are drawn: either the named layer (and its children if it's a
+    /// group), or the visible layers. Reference layers are never drawn.
+    fn included_layers(&self) -> Result<Vec<bool>, AseError> {
+        let ancestors = |mut index: usize| {
+            let mut chain = vec![index];
+            while let Some(parent) = self.layers[index].parent {
+                chain.push(parent);
+                index = parent;
+            }
+            chain
+        };
+        let included: Vec<_> = (0..self.layers.len())
+            .map(|index| {
+                let layer = &self.layers[index];
+                !layer.is_group
+                    && layer.flags & REFERENCE == 0
+                    && match &self.options.layer {
+                        Some(name) => ancestors(index)
+                            .into_iter()
+                            .any(|layer| &self.layers[layer].name == name),
+                        None => ancestors(index)
+                            .into_iter()
+                            .all(|layer| self.layers[layer].flags & VISIBLE != 0),
+                    }
+            })
+            .collect();
+
+        match &self.options.layer {
+            Some(name) if !self.layers.iter().any(|layer| &layer.name == name) => {
+                Err(AseError::NoLayer(name.clone()))
+            }
+            _ => Ok(included),
+        }
+    }
+
+    /// Returns a cel's size and pixels, following links.
+    fn cel_image<'a>(&'a self, cel: &'a Cel) -> Result<(u16, u16, &'a [u8]), AseError> {
+        match &cel.contents {
+            CelContents::Image {
+                width,
+                height,
+                pixels,
+            } => Ok((*width, *height, pixels)),
+            CelContents::Linked(frame) => self
+                .frames
+                .get(usize::from(*frame))
+                .and_then(|cels| {
+                    cels.iter().find(|other| {
+                        other.layer == cel.layer
+                            && !matches!(other.contents, CelContents::Linked(_))
+                    })
+                })
+                .ok_or(AseError::Corrupt("bad linked cel"))
+                .and_then(|other| self.cel_image(other)),
+            CelContents::Unsupported(kind) => Err(AseError::UnsupportedCel(*kind)),
+        }
+    }
+}
+
+/// Decompresses a zlib stream, but no further than `len` bytes, so that a small cel can't claim
+/// to hold far more pixels than it has room for.
+fn inflate(data: &[u8], len: usize) -> Result<Vec<u8>, AseError> {
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+
+    let mut pixels = vec![0; len];
+    let (status, _, out_len) = decompress(
+        &mut Box::<DecompressorOxide>::default(),
+        data,
+        &mut Cursor::new(&mut pixels[..]),
+        inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER
+            | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+    match status {
+        // Anything past the cel's size is ignored, like with uncompressed cels
+        TINFLStatus::Done | TINFLStatus::HasMoreOutput => {
+            pixels.truncate(out_len);
+            Ok(pixels)
+        }
+        _ => Err(AseError::Corrupt("bad compressed cel")),
+    }
+}
+
+/// Draws a color over another, with the given extra opacity.
+fn blend(dest: &mut Color, (red, green, blue, alpha): (u8, u8, u8, u8), opacity: u32) {
+    let alpha = u32::from(alpha) * opacity / 255;
+    let dest_alpha = u32::from(dest.rgba()[3]);
+    // Avoid introducing rounding errors when not actually blending
+    if alpha == 0 {
+        return;
+    }
+    if alpha == 255 || dest_alpha == 0 {
+        *dest = Color::new((red, green, blue, alpha as u8), None);
+        return;
+    }
+
+    let [dest_red, dest_green, dest_blue, _] = dest.rgba();
+    let under = dest_alpha * (255 - alpha) / 255; // How much of the destination shows through
+    let out_alpha = alpha + under;
+    let mix = |src: u8, dst: u8| {
+        ((u32::from(src) * alpha + u32::from(dst) * under + out_alpha / 2) / out_alpha) as u8
+    };
+    *dest = Color::new(
+        (
+            mix(red, dest_red),
+            mix(green, dest_green),
+            mix(blue, dest_blue),
+            out_alpha as u8,
+        ),
+        None,
+    );
+}
+
+#[derive(Debug)]
+pub enum AseError {
+    Io(io::Error),
+    NotAse,
+    BadIndex(u8),
+    BadSlice(String),
+    Corrupt(&'static str),
+    NoFrame(u16, u16), // Requested, how many there are
+    NoLayer(String),
+    TooBig(u32, u32), // width, height
+    UnsupportedCel(u16),
+    UnsupportedDepth(u16),
+}
+
+impl Display for AseError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        use AseError::*;
+
+        match self {
+            Io(err) => err.fmt(fmt),
+            NotAse => write!(fmt, "Not an Aseprite file"),
+            BadIndex(index) => write!(fmt, "Color index {} is not in the palette", index),
+            BadSlice(name) => write!(
+                fmt,
+                "Slice \"{}\" is not made of whole tiles within the image",
+                name
+            ),
+            Corrupt(what) => write!(fmt, "Corrupted Aseprite file: {}", what),
+            NoFrame(frame, nb_frames) => write!(
+                fmt,
+                "Frame {} does not exist (the file only has {})",
+                frame, nb_frames
+            ),
+            NoLayer(name) => write!(fmt, "There is no layer named \"{}\"", name),
+            TooBig(w, h) => write!(fmt, "Image too big! ({} px wide, {} px tall)", w, h),
+            UnsupportedCel(kind) => write!(fmt, "Unsupported Aseprite cel type {}", kind),
+            UnsupportedDepth(depth) => write!(fmt, "Unsupported Aseprite color depth {}", depth),
+        }
+    }
+}
+
+impl error::Error for AseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AseError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(kind: u16, body: &[u8]) -> Vec<u8> {
+        let mut chunk = (body.len() as u32 + 6).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&kind.to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    fn layer(flags: u16, kind: u16, level: u16, name: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        for word in &[flags, kind, level, 0, 0, 0] {
+            body.extend_from_slice(&word.to_le_bytes());
+        }
+        body.extend_from_slice(&[255, 0, 0, 0]);
+        body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+        chunk(LAYER, &body)
+    }
+
+    /// A raw cel at (x, 0)
+    fn cel(layer: u16, x: i16, width: u16, height: u16, pixels: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&layer.to_le_bytes());
+        body.extend_from_slice(&x.to_le_bytes());
+        body.extend_from_slice(&[0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&width.to_le_bytes());
+        body.extend_from_slice(&height.to_le_bytes());
+        body.extend_from_slice(pixels);
+        chunk(CEL, &body)
+    }
+
+    fn palette(colors: &[(u8, u8, u8)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for dword in &[colors.len() as u32, 0, colors.len() as u32 - 1, 0, 0] {
+            body.extend_from_slice(&dword.to_le_bytes());
+        }
+        for &(r, g, b) in colors {
+            body.extend_from_slice(&[0, 0, r, g, b, 255]);
+        }
+        chunk(PALETTE, &body)
+    }
+
+    fn slice(name: &str, x: i32, width: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&x.to_le_bytes());
+        body.extend_from_slice(&0i32.to_le_bytes());
+        body.extend_from_slice(&width.to_le_bytes());
+        body.extend_from_slice(&8u32.to_le_bytes());
+        chunk(SLICE, &body)
+    }
+
+    fn file(width: u16, height: u16, depth: u16, frames: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        let mut file = vec![0; 128];
+        file[4..6].copy_from_slice(&0xA5E0u16.to_le_bytes());
+        file[6..8].copy_from_slice(&(frames.len() as u16).to_le_bytes());
+        file[8..10].copy_from_slice(&width.to_le_bytes());
+        file[10..12].copy_from_slice(&height.to_le_bytes());
+        file[12..14].copy_from_slice(&depth.to_le_bytes());
+        for chunks in frames {
+            let body: Vec<u8> = chunks.concat();
+            file.extend_from_slice(&(body.len() as u32 + 16).to_le_bytes());
+            file.extend_from_slice(&0xF1FAu16.to_le_bytes());
+            file.extend_from_slice(&(chunks.len() as u16).to_le_bytes());
+            file.extend_from_slice(&[0; 8]);
+            file.extend_from_slice(&body);
+        }
+        file
+    }
+
+    fn read(file: &[u8], options: AseOptions) -> Result<Image, AseError> {
+        let mut reader = AseReader::new(file).unwrap();
+        reader.set_options(&options);
+        reader.read_image()
+    }
+
+    #[test]
+    fn indexed_layers() {
+        // Index 0 is transparent; the top layer only covers the left pixel
+        let file = file(
+            2,
+            1,
+            8,
+            &[vec![
+                palette(&[(0, 0, 0), (255, 0, 0), (0, 255, 0)]),
+                layer(VISIBLE, 0, 0, "bottom"),
+                layer(VISIBLE, 0, 0, "top"),
+                layer(0, 0, 0, "hidden"),
+                cel(0, 0, 2, 1, &[1, 1]),
+                cel(1, 0, 2, 1, &[2, 0]),
+                cel(2, 0, 2, 1, &[0, 0]),
+            ]],
+        );
+        let img = read(&file, AseOptions::default()).unwrap();
+        let indices: Vec<_> = img.pixels().iter().map(Color::palette_index).collect();
+        assert_eq!(indices, [Some(2), Some(1)]);
+
+        // Picking a layer ignores the others, even if it's hidden
+        let options = AseOptions {
+            layer: Some("hidden".to_string()),
+            ..AseOptions::default()
+        };
+        let img = read(&file, options).unwrap();
+        assert_eq!(img.pixels()[0], Color::new((0, 0, 0, 0), Some(0)));
+
+        let options = AseOptions {
+            layer: Some("nope".to_string()),
+            ..AseOptions::default()
+        };
+        assert!(matches!(read(&file, options), Err(AseError::NoLayer(_))));
+    }
+
+    #[test]
+    fn hidden_group() {
+        let file = file(
+            1,
+            1,
+            32,
+            &[vec![
+                layer(0, 1, 0, "group"),
+                layer(VISIBLE, 0, 1, "child"),
+                layer(VISIBLE, 0, 0, "outside"),
+                cel(1, 0, 1, 1, &[255, 0, 0, 255]),
+                cel(2, 0, 1, 1, &[0, 0, 255, 128]),
+            ]],
+        );
+        let img = read(&file, AseOptions::default()).unwrap();
+        assert_eq!(img.pixels()[0], Color::new((0, 0, 255, 128), None));
+    }
+
+    #[test]
+    fn blending() {
+        let mut dest = Color::new((255, 0, 0, 255), None);
+        blend(&mut dest, (0, 0, 255, 255), 255);
+        assert_eq!(dest, Color::new((0, 0, 255, 255), None));
+        blend(&mut dest, (255, 0, 0, 255), 0);
+        assert_eq!(dest, Color::new((0, 0, 255, 255), None));
+        blend(&mut dest, (255, 0, 0, 255), 128);
+        assert_eq!(dest, Color::new((128, 0, 127, 255), None));
+    }
+
+    #[test]
+    fn frames_and_slices() {
+        let file = file(
+            8,
+            8,
+            8,
+            &[
+                vec![
+                    palette(&[(0, 0, 0), (255, 255, 255)]),
+                    layer(VISIBLE, 0, 0, "layer"),
+                    slice("hero", 0, 8),
+                    cel(0, 0, 1, 1, &[1]),
+                ],
+                vec![cel(0, 1, 1, 1, &[1])],
+            ],
+        );
+        let options = AseOptions {
+            frames: AseFrames::All,
+            ..AseOptions::default()
+        };
+        let img = read(&file, options.clone()).unwrap();
+        assert_eq!(img.width(), 16);
+        assert_eq!(img[(0, 0)].palette_index(), Some(1));
+        assert_eq!(img[(9, 0)].palette_index(), Some(1));
+        assert_eq!(img[(8, 0)], Color::TRANSPARENT);
+
+        let mut reader = AseReader::new(&file[..]).unwrap();
+        reader.set_options(&options);
+        assert_eq!(
+            reader.slices().unwrap(),
+            [
+                Slice {
+                    x: 0,
+                    y: 0,
+                    width: 1,
+                    height: 1
+                },
+                Slice {
+                    x: 8,
+                    y: 0,
+                    width: 1,
+                    height: 1
+                }
+            ]
+        );
+
+        let options = AseOptions {
+            frames: AseFrames::One(2),
+            ..AseOptions::default()
+        };
+        assert!(matches!(read(&file, options), Err(AseError::NoFrame(2, 2))));
+    }
+
+    #[test]
+    fn too_big() {
+        // Fine on its own, but not once all frames are laid side by side
+        let wide = file(
+            8192,
+            8192,
+            32,
+            &[vec![layer(VISIBLE, 0, 0, "layer")], vec![]],
+        );
+        let options = AseOptions {
+            frames: AseFrames::All,
+            ..AseOptions::default()
+        };
+        assert!(matches!(
+            read(&wide, options),
+            Err(AseError::TooBig(16384, 8192))
+        ));
+
+        let mut body = Vec::new();
+        for dword in &[0x10000u32, 0, 0, 0, 0] {
+            body.extend_from_slice(&dword.to_le_bytes());
+        }
+        body.extend_from_slice(&[0, 0, 0, 0, 0, 255]);
+        let bad_palette = file(1, 1, 8, &[vec![chunk(PALETTE, &body)]]);
+        assert!(matches!(
+            AseReader::new(&bad_palette[..]),
+            Err(AseError::Corrupt("palette too big"))
+        ));
+
+        let mut bad_frame = file(1, 1, 8, &[vec![], vec![]]);
+        bad_frame[128..132].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            AseReader::new(&bad_frame[..]),
+            Err(AseError::Corrupt("frame too small"))
+        ));
+    }
+
+    #[test]
+    fn compressed_cels() {
+        // Sets a cel's type, which comes after its chunk's header, layer, position and opacity
+        let kind = |mut cel: Vec<u8>, kind: u16| {
+            cel[13..15].copy_from_slice(&kind.to_le_bytes());
+            cel
+        };
+        // More data than the cel needs is ignored
+        let pixels = miniz_oxide::deflate::compress_to_vec_zlib(&[1, 2, 3], 6);
+        let longer = file(
+            2,
+            1,
+            8,
+            &[vec![
+                palette(&[(0, 0, 0), (255, 0, 0), (0, 255, 0)]),
+                layer(VISIBLE, 0, 0, "layer"),
+                kind(cel(0, 0, 2, 1, &pixels), 2),
+            ]],
+        );
+        let img = read(&longer, AseOptions::default()).unwrap();
+        let indices: Vec<_> = img.pixels().iter().map(Color::palette_index).collect();
+        assert_eq!(indices, [Some(1), Some(2)]);
+
+        let pixels = miniz_oxide::deflate::compress_to_vec_zlib(&[1], 6);
+        let truncated = file(2, 1, 8, &[vec![kind(cel(0, 0, 2, 1, &pixels), 2)]]);
+        assert!(matches!(
+            AseReader::new(&truncated[..]),
+            Err(AseError::Corrupt("truncated cel"))
+        ));
+        let huge = file(1, 1, 8, &[vec![kind(cel(0, 0, 65535, 65535, &pixels), 2)]]);
+        assert!(matches!(
+            AseReader::new(&huge[..]),
+            Err(AseError::TooBig(65535, 65535))
+        ));
+        let garbage = file(2, 1, 8, &[vec![kind(cel(0, 0, 2, 1, &[1, 2, 3]), 2)]]);
+        assert!(matches!(
+            AseReader::new(&garbage[..]),
+            Err(AseError::Corrupt("bad compressed cel"))
+        ));
+    }
+
+    #[test]
+    fn tilemap_cels() {
+        let mut tilemap = cel(1, 0, 1, 1, &[]);
+        tilemap[13..15].copy_from_slice(&3u16.to_le_bytes());
+        let file = file(
+            1,
+            1,
+            32,
+            &[vec![
+                layer(VISIBLE, 0, 0, "image"),
+                layer(0, 2, 0, "tilemap"),
+                cel(0, 0, 1, 1, &[255, 0, 0, 255]),
+                tilemap,
+            ]],
+        );
+        // Hidden layers can hold anything
+        let img = read(&file, AseOptions::default()).unwrap();
+        assert_eq!(img.pixels()[0], Color::new((255, 0, 0, 255), None));
+
+        let options = AseOptions {
+            layer: Some("tilemap".to_string()),
+            ..AseOptions::default()
+        };
+        assert!(matches!(
+            read(&file, options),
+            Err(AseError::UnsupportedCel(3))
+        ));
+    }
+
+    #[test]
+    fn frame_names() {
+        assert_eq!("all".parse::<AseFrames>().unwrap(), AseFrames::All);
+        assert_eq!("3".parse::<AseFrames>().unwrap(), AseFrames::One(3));
+        assert!("-1".parse::<AseFrames>().is_err());
+    }
+}
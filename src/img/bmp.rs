@@ -0,0 +1,547 @@
+use super::{Color, Image, ImageReader, MAX_PIXELS};
+use std::convert::{TryFrom, TryInto};
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+
+// Compression methods
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+pub struct BmpReader<R: Read> {
+    input: R,
+    width: u32,
+    height: u32,
+    top_down: bool,
+    bpp: u16,
+    compression: u32,
+    masks: [u32; 4], // Red, green, blue, alpha; only used for 16- and 32-bit images
+    palette: Option<Vec<Color>>,
+    gap: u32, // How many bytes lie between the headers and the pixel data
+}
+
+fn u16_at(buf: &[u8], ofs: usize) -> u16 {
+    u16::from_le_bytes(buf[ofs..ofs + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], ofs: usize) -> u32 {
+    u32::from_le_bytes(buf[ofs..ofs + 4].try_into().unwrap())
+}
+
+impl<R: Read> ImageReader<R> for BmpReader<R> {
+    type NewError = BmpError;
+
+    fn new(mut input: R) -> Result<Self, Self::NewError> {
+        let mut file_header = [0; 14];
+        input.read_exact(&mut file_header)?;
+        if &file_header[..2] != b"BM" {
+            return Err(BmpError::NotBmp);
+        }
+        let data_ofs = u32_at(&file_header, 10);
+
+        let mut header_size = [0; 4];
+        input.read_exact(&mut header_size)?;
+        let header_size = u32::from_le_bytes(header_size);
+        // Newer headers only add fields that we don't need, but keep it reasonable
+        if header_size != 12 && !(40..=256).contains(&header_size) {
+            return Err(BmpError::UnsupportedHeader(header_size));
+        }
+        let mut header = vec![0; usize::try_from(header_size).unwrap() - 4];
+        input.read_exact(&mut header)?;
+        let mut nb_read = 14 + header_size;
+
+        // OS/2 1.x headers (a.k.a. `BITMAPCOREHEADER`) are much more compact
+        let (width, height, bpp, compression, nb_colors, entry_size) = if header_size == 12 {
+            let height = i32::from(u16_at(&header, 2));
+            (
+                i32::from(u16_at(&header, 0)),
+                height,
+                u16_at(&header, 6),
+                BI_RGB,
+                0,
+                3,
+            )
+        } else {
+            let height = u32_at(&header, 4) as i32; // Negative for top-down images
+            let (bpp, compression) = (u16_at(&header, 10), u32_at(&header, 12));
+            (
+                u32_at(&header, 0) as i32,
+                height,
+                bpp,
+                compression,
+                u32_at(&header, 28),
+                4,
+            )
+        };
+        let width = u32::try_from(width).map_err(|_| BmpError::Corrupt("negative width"))?;
+        if u64::from(width) * u64::from(height.unsigned_abs()) > MAX_PIXELS as u64 {
+            return Err(BmpError::TooBig(width, height.unsigned_abs()));
+        }
+
+        // Only 16- and 32-bit images may have their channels laid out arbitrarily
+        let mut masks = match bpp {
+            16 => [0x7C00, 0x3E0, 0x1F, 0],
+            _ => [0xFF0000, 0xFF00, 0xFF, 0],
+        };
+        match (compression, bpp) {
+            (BI_RGB, 1) | (BI_RGB, 4) | (BI_RGB, 8) | (BI_RGB, 16) | (BI_RGB, 24) => (),
+            (BI_RGB, 32) => (),
+            (BI_RLE8, 8) | (BI_RLE4, 4) => (),
+            (BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) => {
+                masks = read_masks(&mut input, &header, 3, &mut nb_read)?
+            }
+            (BI_ALPHABITFIELDS, 16) | (BI_ALPHABITFIELDS, 32) => {
+                masks = read_masks(&mut input, &header, 4, &mut nb_read)?
+            }
+            (BI_RGB, _) | (BI_BITFIELDS, _) | (BI_ALPHABITFIELDS, _) => {
+                return Err(BmpError::UnsupportedDepth(bpp))
+            }
+            _ => return Err(BmpError::UnsupportedCompression(compression, bpp)),
+        }
+        // Version 4 headers and above may specify an alpha mask even without `BI_ALPHABITFIELDS`
+        if header_size >= 56 && compression == BI_BITFIELDS {
+            masks[3] = u32_at(&header, 48);
+        }
+
+        let palette = if bpp <= 8 {
+            let max_colors = 1 << bpp;
+            let nb_colors = if nb_colors == 0 {
+                max_colors
+            } else {
+                nb_colors
+            };
+            if nb_colors > max_colors {
+                return Err(BmpError::Corrupt(
+                    "palette larger than the bit depth allows",
+                ));
+            }
+            let mut entries = vec![0; usize::try_from(nb_colors * entry_size).unwrap()];
+            input.read_exact(&mut entries)?;
+            nb_read += nb_colors * entry_size;
+
+            Some(
+                entries
+                    .chunks(usize::try_from(entry_size).unwrap())
+                    .enumerate()
+                    // Palette entries are stored as BGR, and the 4th byte is reserved
+                    .map(|(i, bgr)| {
+                        Color::new(
+                            Color::rgb_to_rgba((bgr[2], bgr[1], bgr[0])),
+                            Some(u8::try_from(i).unwrap()),
+                        )
+                    })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            input,
+            width,
+            height: height.unsigned_abs(),
+            top_down: height < 0,
+            bpp,
+            compression,
+            masks,
+            palette,
+            gap: data_ofs
+                .checked_sub(nb_read)
+                .ok_or(BmpError::Corrupt("pixel data overlaps the headers"))?,
+        })
+    }
+
+    type ReadError = BmpError;
+
+    fn read_image(&mut self) -> Result<Image, Self::ReadError> {
+        let (width, height) = (self.width, self.height);
+        // `new` made sure this is at most `MAX_PIXELS`
+        let nb_pixels = usize::try_from(width * height).unwrap();
+        let row_len = usize::try_from(width).unwrap();
+
+        io::copy(
+            &mut (&mut self.input).take(u64::from(self.gap)),
+            &mut io::sink(),
+        )?;
+        let mut data = Vec::new();
+        self.input.read_to_end(&mut data)?;
+
+        // Pixels are decoded in the order they are stored, rows being flipped afterwards
+        let pixels = if let Some(palette) = &self.palette {
+            let indices = match self.compression {
+                BI_RLE8 | BI_RLE4 => decode_rle(&data, width, height, self.compression == BI_RLE4)?,
+                _ => {
+                    let bpp = usize::from(self.bpp);
+                    let stride = (row_len * bpp).div_ceil(32) * 4;
+                    let mut indices = Vec::with_capacity(nb_pixels);
+                    for y in 0..usize::try_from(height).unwrap() {
+                        let row = data
+                            .get(y * stride..(y + 1) * stride)
+                            .ok_or(BmpError::Corrupt("truncated pixel data"))?;
+                        indices.extend((0..row_len).map(|x| {
+                            let bit = x * bpp;
+                            row[bit / 8] >> (8 - bpp - bit % 8) & ((1 << bpp) - 1) as u8
+                        }));
+                    }
+                    indices
+                }
+            };
+            indices
+                .into_iter()
+                .map(|index| {
+                    palette
+                        .get(usize::from(index))
+                        .cloned()
+                        .ok_or(BmpError::BadIndex(index))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let nb_bytes = usize::from(self.bpp / 8);
+            let stride = (row_len * nb_bytes).div_ceil(4) * 4;
+            let mut pixels = Vec::with_capacity(nb_pixels);
+            for y in 0..usize::try_from(height).unwrap() {
+                let row = data
+                    .get(y * stride..(y + 1) * stride)
+                    .ok_or(BmpError::Corrupt("truncated pixel data"))?;
+                pixels.extend(row.chunks(nb_bytes).take(row_len).map(|bytes| {
+                    if nb_bytes == 3 {
+                        Color::new(Color::rgb_to_rgba((bytes[2], bytes[1], bytes[0])), None)
+                    } else {
+                        let mut value = [0; 4];
+                        value[..nb_bytes].copy_from_slice(bytes);
+                        let value = u32::from_le_bytes(value);
+                        let [red, green, blue, alpha] = self.masks;
+                        Color::new(
+                            (
+                                channel(value, red, 0),
+                                channel(value, green, 0),
+                                channel(value, blue, 0),
+                                channel(value, alpha, 255),
+                            ),
+                            None,
+                        )
+                    }
+                }));
+            }
+            pixels
+        };
+
+        Ok(Image::new(
+            width,
+            height,
+            if self.top_down || row_len == 0 {
+                pixels
+            } else {
+                pixels.chunks(row_len).rev().flatten().cloned().collect()
+            },
+        ))
+    }
+}
+
+impl<R: Read> BmpReader<R> {
+    /// Returns the image's palette, if any; each color carries its index within the palette.
+    pub fn palette(&self) -> Option<Vec<Color>> {
+        self.palette.clone()
+    }
+}
+
+/// Reads the channel masks, which follow `BITMAPINFOHEADER`s, but are part of larger headers.
+fn read_masks<R: Read>(
+    input: &mut R,
+    header: &[u8],
+    nb_masks: usize,
+    nb_read: &mut u32,
+) -> Result<[u32; 4], BmpError> {
+    let mut bytes = [0; 16];
+    // `header` does not include the size field
+    if header.len() >= 36 + nb_masks * 4 {
+        bytes[..nb_masks * 4].copy_from_slice(&header[36..36 + nb_masks * 4]);
+    } else {
+        input.read_exact(&mut bytes[..nb_masks * 4])?;
+        *nb_read += u32::try_from(nb_masks * 4).unwrap();
+    }
+    Ok([
+        u32_at(&bytes, 0),
+        u32_at(&bytes, 4),
+        u32_at(&bytes, 8),
+        u32_at(&bytes, 12),
+    ])
+}
+
+/// Extracts a channel from a pixel, scaling it to 8 bits; `default` is used if the mask is empty.
+fn channel(value: u32, mask: u32, default: u8) -> u8 {
+    if mask == 0 {
+        return default;
+    }
+    let max = u64::from(mask >> mask.trailing_zeros());
+    let value = u64::from((value & mask) >> mask.trailing_zeros());
+    ((value * 255 + max / 2) / max) as u8
+}
+
+/// Decodes RLE-compressed indices; pixels skipped over are set to index 0.
+fn decode_rle(data: &[u8], width: u32, height: u32, rle4: bool) -> Result<Vec<u8>, BmpError> {
+    let (width, height) = (
+        usize::try_from(width).unwrap(),
+        usize::try_from(height).unwrap(),
+    );
+    let mut indices = vec![0; width * height];
+    let (mut x, mut y) = (0, 0);
+    let mut bytes = data.iter().copied();
+    let mut next = || bytes.next().ok_or(BmpError::Corrupt("truncated RLE data"));
+    let mut put = |x: &mut usize, y: usize, index: u8| {
+        if *x >= width || y >= height {
+            return Err(BmpError::Corrupt("RLE data overflows the image"));
+        }
+        indices[y * width + *x] = index;
+        *x += 1;
+        Ok(())
+    };
+    let nibble = |byte: u8, i: u8| {
+        if i % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xF
+        }
+    };
+
+    loop {
+        let (count, value) = (next()?, next()?);
+        if count != 0 {
+            // Encoded mode: a run of the same index (or of two alternating ones, in RLE4)
+            for i in 0..count {
+                put(&mut x, y, if rle4 { nibble(value, i) } else { value })?;
+            }
+            continue;
+        }
+        match value {
+            0 => {
+                x = 0;
+                y += 1;
+            }
+            1 => break,
+            2 => {
+                x += usize::from(next()?);
+                y += usize::from(next()?);
+            }
+            count => {
+                // Absolute mode: `count` literal indices, padded to a 16-bit boundary
+                let nb_bytes = if rle4 { count.div_ceil(2) } else { count };
+                let mut byte = 0;
+                for i in 0..count {
+                    if !rle4 || i % 2 == 0 {
+                        byte = next()?;
+                    }
+                    put(&mut x, y, if rle4 { nibble(byte, i) } else { byte })?;
+                }
+                if nb_bytes % 2 != 0 {
+                    next()?;
+                }
+            }
+        }
+    }
+
+    Ok(indices)
+}
+
+#[derive(Debug)]
+pub enum BmpError {
+    Io(io::Error),
+    NotBmp,
+    BadIndex(u8),
+    Corrupt(&'static str),
+    TooBig(u32, u32), // width, height
+    UnsupportedCompression(u32, u16),
+    UnsupportedDepth(u16),
+    UnsupportedHeader(u32),
+}
+
+impl Display for BmpError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        use BmpError::*;
+
+        match self {
+            Io(err) => err.fmt(fmt),
+            NotBmp => write!(fmt, "Not a BMP file"),
+            BadIndex(index) => write!(fmt, "Color index {} is not in the palette", index),
+            Corrupt(what) => write!(fmt, "Corrupted BMP file: {}", what),
+            TooBig(w, h) => write!(fmt, "Image too big! ({} px wide, {} px tall)", w, h),
+            UnsupportedCompression(compression, bpp) => write!(
+                fmt,
+                "Unsupported BMP compression method {} for {}-bit images",
+                compression, bpp
+            ),
+            UnsupportedDepth(bpp) => write!(fmt, "Unsupported BMP bit depth {}", bpp),
+            UnsupportedHeader(size) => write!(fmt, "Unsupported BMP header ({} bytes)", size),
+        }
+    }
+}
+
+impl error::Error for BmpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BmpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BmpError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a BMP file with a `BITMAPINFOHEADER`.
+    fn bmp(
+        width: i32,
+        height: i32,
+        bpp: u16,
+        compression: u32,
+        palette: &[u32],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let data_ofs = 14 + 40 + palette.len() as u32 * 4;
+        let mut file = Vec::new();
+        file.extend_from_slice(b"BM");
+        file.extend_from_slice(&(data_ofs + data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&data_ofs.to_le_bytes());
+        file.extend_from_slice(&40u32.to_le_bytes());
+        file.extend_from_slice(&width.to_le_bytes());
+        file.extend_from_slice(&height.to_le_bytes());
+        file.extend_from_slice(&1u16.to_le_bytes());
+        file.extend_from_slice(&bpp.to_le_bytes());
+        file.extend_from_slice(&compression.to_le_bytes());
+        file.extend_from_slice(&[0; 12]);
+        file.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        for color in palette {
+            file.extend_from_slice(&color.to_le_bytes());
+        }
+        file.extend_from_slice(data);
+        file
+    }
+
+    fn read(file: &[u8]) -> (Image, Option<Vec<Color>>) {
+        let mut reader = BmpReader::new(file).unwrap();
+        let palette = reader.palette();
+        (reader.read_image().unwrap(), palette)
+    }
+
+    fn rgb(rgb: (u8, u8, u8)) -> Color {
+        Color::new(Color::rgb_to_rgba(rgb), None)
+    }
+
+    #[test]
+    fn indexed_bottom_up() {
+        // 2x2, 4-bit; the bottom row comes first
+        let file = bmp(
+            2,
+            2,
+            4,
+            BI_RGB,
+            &[0x000000, 0xFF0000, 0x00FF00],
+            &[0x12, 0, 0, 0, 0x20, 0, 0, 0],
+        );
+        let (img, palette) = read(&file);
+        let palette = palette.unwrap();
+        assert_eq!(palette[1], Color::new((255, 0, 0, 255), Some(1)));
+        let indices: Vec<_> = img.pixels().iter().map(Color::palette_index).collect();
+        assert_eq!(indices, [Some(2), Some(0), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn one_bit() {
+        let file = bmp(
+            10,
+            1,
+            1,
+            BI_RGB,
+            &[0x000000, 0xFFFFFF],
+            &[0b1010_0000, 0b0100_0000, 0, 0],
+        );
+        let (img, _) = read(&file);
+        let indices: Vec<_> = img
+            .pixels()
+            .iter()
+            .map(|pixel| pixel.palette_index().unwrap())
+            .collect();
+        assert_eq!(indices, [1, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn true_color_top_down() {
+        // 24-bit BGR, each row padded to 4 bytes
+        let data = [0, 0, 255, 0, 255, 0, 0, 0, 255, 0, 0, 10, 20, 30, 0, 0];
+        let (img, palette) = read(&bmp(2, -2, 24, BI_RGB, &[], &data));
+        assert!(palette.is_none());
+        assert_eq!(
+            img.pixels(),
+            [
+                rgb((255, 0, 0)),
+                rgb((0, 255, 0)),
+                rgb((0, 0, 255)),
+                rgb((30, 20, 10))
+            ]
+        );
+
+        // 32-bit BGRX
+        let (img, _) = read(&bmp(1, 1, 32, BI_RGB, &[], &[1, 2, 3, 0]));
+        assert_eq!(img.pixels(), [rgb((3, 2, 1))]);
+    }
+
+    #[test]
+    fn rle() {
+        // A run of 3, then absolute mode with 3 indices (padded), end of line, delta, end of bitmap
+        let data = [3, 1, 0, 3, 2, 0, 1, 0, 0, 0, 0, 2, 1, 1, 1, 2, 0, 1];
+        let (img, _) = read(&bmp(6, 3, 8, BI_RLE8, &[0, 0xFF, 0xFF00], &data));
+        let indices: Vec<_> = img
+            .pixels()
+            .iter()
+            .map(|pixel| pixel.palette_index().unwrap())
+            .collect();
+        // Rows are stored bottom-up
+        assert_eq!(
+            indices,
+            [0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 2, 0, 1]
+        );
+
+        // RLE4 alternates between both nibbles
+        let (img, _) = read(&bmp(4, 1, 4, BI_RLE4, &[0, 0xFF], &[4, 0x10, 0, 1]));
+        let indices: Vec<_> = img
+            .pixels()
+            .iter()
+            .map(|pixel| pixel.palette_index().unwrap())
+            .collect();
+        assert_eq!(indices, [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn too_big() {
+        let file = bmp(65535, -65535, 24, BI_RGB, &[], &[]);
+        assert!(matches!(
+            BmpReader::new(&file[..]),
+            Err(BmpError::TooBig(65535, 65535))
+        ));
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(matches!(
+            BmpReader::new(&b"\x89PNG\r\n\x1a\n......"[..]),
+            Err(BmpError::NotBmp)
+        ));
+        let file = bmp(2, 1, 8, BI_RGB, &[0], &[0, 1, 0, 0]);
+        assert!(matches!(
+            BmpReader::new(&file[..]).unwrap().read_image(),
+            Err(BmpError::BadIndex(1))
+        ));
+    }
+}
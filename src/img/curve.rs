@@ -0,0 +1,166 @@
+use super::Color;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Models of how various screens display RGB555 colors
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Curve {
+    /// Colors are displayed as-is
+    Identity,
+    /// The GBC's LCD, which washes colors out quite a bit
+    Cgb,
+    /// The GBA's LCD, running GBC software; darker than the GBC's
+    Agb,
+    /// The SGB, displaying on a CRT TV
+    Sgb,
+}
+
+impl Curve {
+    /// Computes the RGB888 color that the screen displays for a given RGB555 color.
+    pub fn display(self, color: u16) -> (u8, u8, u8) {
+        let (r, g, b) = (
+            f32::from(color & 0x1F),
+            f32::from(color >> 5 & 0x1F),
+            f32::from(color >> 10 & 0x1F),
+        );
+        let to_u8 = |channel: f32| channel.clamp(0.0, 255.0).round() as u8;
+
+        match self {
+            Curve::Identity => (
+                to_u8(r * 255.0 / 31.0),
+                to_u8(g * 255.0 / 31.0),
+                to_u8(b * 255.0 / 31.0),
+            ),
+            // Channels bleed into each other, and saturate before reaching full brightness
+            Curve::Cgb => {
+                let mix = |red: f32, green: f32, blue: f32| {
+                    to_u8((red * r + green * g + blue * b).min(960.0) / 4.0)
+                };
+                (
+                    mix(26.0, 4.0, 2.0),
+                    mix(0.0, 24.0, 8.0),
+                    mix(6.0, 4.0, 22.0),
+                )
+            }
+            // The LCD has a much steeper gamma than the sRGB output, and also bleeds colors
+            Curve::Agb => {
+                let (lr, lg, lb) = ((r / 31.0).powi(4), (g / 31.0).powi(4), (b / 31.0).powi(4));
+                let mix = |red: f32, green: f32, blue: f32| {
+                    let linear = (red * lr + green * lg + blue * lb) / 255.0;
+                    to_u8(linear.powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0)
+                };
+                (
+                    mix(255.0, 50.0, 0.0),
+                    mix(10.0, 230.0, 30.0),
+                    mix(50.0, 10.0, 220.0),
+                )
+            }
+            // The SNES' output is linear, but CRTs have a slightly steeper gamma than sRGB
+            Curve::Sgb => {
+                let gamma = |channel: f32| to_u8((channel / 31.0).powf(2.5 / 2.2) * 255.0);
+                (gamma(r), gamma(g), gamma(b))
+            }
+        }
+    }
+
+    /// Replaces each color by the one whose display is closest to it.
+    pub fn correct_palettes(self, palettes: &mut [[Color; 4]]) {
+        // Compute what every RGB555 color looks like once, since there are only 32768 of them
+        let luma_chroma = |curve: Curve, color| {
+            Color::new(Color::rgb_to_rgba(curve.display(color)), None).luma_chroma()
+        };
+        let displayed: Vec<_> = (0..0x8000).map(|color| luma_chroma(self, color)).collect();
+        let intended: Vec<_> = (0..0x8000)
+            .map(|color| luma_chroma(Curve::Identity, color))
+            .collect();
+
+        for color in palettes.iter_mut().flat_map(|palette| palette.iter_mut()) {
+            let (y, u, v) = color.luma_chroma();
+            let distance = |(dy, du, dv): (f32, f32, f32)| {
+                (dy - y).powi(2) + (du - u).powi(2) + (dv - v).powi(2)
+            };
+            // Several colors may be displayed identically (e.g. when saturating); in that case,
+            // pick the one closest to the intended color without correction
+            let (_, closest) = (0..0x8000)
+                .map(|candidate: u16| {
+                    let idx = usize::from(candidate);
+                    let key = (distance(displayed[idx]), distance(intended[idx]));
+                    (key, candidate)
+                })
+                .min_by(|(lhs, _), (rhs, _)| lhs.partial_cmp(rhs).unwrap())
+                .unwrap();
+
+            let [red, green, blue, _] = Color::from_rgb555(closest, None).rgba();
+            *color = Color::new((red, green, blue, color.rgba()[3]), color.palette_index());
+        }
+    }
+}
+
+impl FromStr for Curve {
+    type Err = UnknownCurve;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "identity" => Ok(Curve::Identity),
+            "cgb" => Ok(Curve::Cgb),
+            "agb" => Ok(Curve::Agb),
+            "sgb" => Ok(Curve::Sgb),
+            _ => Err(UnknownCurve(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownCurve(String);
+
+impl Display for UnknownCurve {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Unknown color curve \"{}\"", self.0)
+    }
+}
+
+impl error::Error for UnknownCurve {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::Rounding;
+
+    fn correct(curve: Curve, rgb: (u8, u8, u8)) -> u16 {
+        let mut palettes = [[
+            Color::new(Color::rgb_to_rgba(rgb), None),
+            Color::default(),
+            Color::default(),
+            Color::default(),
+        ]];
+        curve.correct_palettes(&mut palettes);
+        palettes[0][0].to_rgb555(Rounding::default())
+    }
+
+    #[test]
+    fn identity_round_trips() {
+        for &color in &[0x0000, 0x7FFF, 0x001F, 0x03E0, 0x7C00, 0x1234, 0x4A52] {
+            assert_eq!(
+                correct(Curve::Identity, Curve::Identity.display(color)),
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn extremes() {
+        for &curve in &[Curve::Identity, Curve::Cgb, Curve::Agb, Curve::Sgb] {
+            assert_eq!(correct(curve, (0, 0, 0)), 0x0000, "{:?}", curve);
+            assert_eq!(correct(curve, (255, 255, 255)), 0x7FFF, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn cgb_compensates_bleeding() {
+        // Green bleeds into blue on the GBC, so pure green needs less blue than a naive conversion
+        let color = correct(Curve::Cgb, (0, 255, 0));
+        assert_eq!(color >> 10, 0);
+        assert!(color >> 5 & 0x1F > 0x10);
+    }
+}
@@ -0,0 +1,265 @@
+use super::{
+    AseError, AseOptions, AseReader, BmpError, BmpReader, Collapsed, Color, GifError, GifReader,
+    Image, ImageReader, PngOptions, PngReadError, PngReader,
+};
+use crate::args::Slice;
+use png::DecodingError;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// The image formats that can be read
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    Ase,
+    Bmp,
+    Gif,
+    Png,
+}
+
+/// Each format's signature, and its offset within the file
+static SIGNATURES: [(Format, usize, &[u8]); 5] = [
+    // http://www.libpng.org/pub/png/spec/iso/index-object.html#5PNG-file-signature
+    (Format::Png, 0, b"\x89PNG\r\n\x1a\n"),
+    (Format::Bmp, 0, b"BM"),
+    (Format::Gif, 0, b"GIF87a"),
+    (Format::Gif, 0, b"GIF89a"),
+    (Format::Ase, 4, b"\xE0\xA5"), // Preceded by the file size
+];
+
+/// How many bytes must be read to detect any format
+const SIGNATURE_LEN: usize = 8;
+
+impl Format {
+    /// Identifies a file's format from its first bytes.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        SIGNATURES
+            .iter()
+            .find(|(_, ofs, signature)| {
+                header
+                    .get(*ofs..ofs + signature.len())
+                    .is_some_and(|bytes| bytes == *signature)
+            })
+            .map(|&(format, _, _)| format)
+    }
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "ase" | "aseprite" => Ok(Format::Ase),
+            "bmp" => Ok(Format::Bmp),
+            "gif" => Ok(Format::Gif),
+            "png" => Ok(Format::Png),
+            _ => Err(UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownFormat(String);
+
+impl Display for UnknownFormat {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Unknown image format \"{}\"", self.0)
+    }
+}
+
+impl error::Error for UnknownFormat {}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub format: Option<Format>, // If `None`, detected from the file's signature
+    pub ase: AseOptions,
+    pub png: PngOptions,
+}
+
+/// Everything read from an image file
+pub struct Decoded {
+    pub image: Image,
+    pub palette: Option<Vec<Color>>, // Each color carries its index
+    pub slices: Option<Vec<Slice>>,  // Only if the file has some, and they were asked for
+    pub collapsed: Vec<Collapsed>,   // Only for 16-bit PNGs, if asked for
+}
+
+/// Reads an image with the reader for its format.
+pub fn read<R: Read>(mut input: R, options: &ReadOptions) -> Result<Decoded, ReadError> {
+    let mut header = Vec::with_capacity(SIGNATURE_LEN);
+    input
+        .by_ref()
+        .take(SIGNATURE_LEN as u64)
+        .read_to_end(&mut header)?;
+    let format = match options.format {
+        Some(format) => format,
+        None => Format::detect(&header).ok_or(ReadError::UnknownFormat)?,
+    };
+    // Give the reader back the bytes consumed by detection
+    let input = header.as_slice().chain(input);
+
+    match format {
+        Format::Ase => {
+            let mut reader = AseReader::new(input)?;
+            reader.set_options(&options.ase);
+            let slices = if options.ase.slices {
+                Some(reader.slices()?)
+            } else {
+                None
+            };
+            Ok(Decoded {
+                palette: reader.palette(),
+                image: reader.read_image()?,
+                slices,
+                collapsed: Vec::new(),
+            })
+        }
+        Format::Bmp => {
+            let mut reader = BmpReader::new(input)?;
+            Ok(Decoded {
+                palette: reader.palette(),
+                image: reader.read_image()?,
+                slices: None,
+                collapsed: Vec::new(),
+            })
+        }
+        Format::Gif => {
+            let mut reader = GifReader::new(input)?;
+            Ok(Decoded {
+                palette: reader.palette(),
+                image: reader.read_image()?,
+                slices: None,
+                collapsed: Vec::new(),
+            })
+        }
+        Format::Png => {
+            let mut reader = PngReader::new(input)?;
+            reader.set_options(options.png);
+            Ok(Decoded {
+                palette: reader.palette(),
+                image: reader.read_image()?,
+                slices: None,
+                collapsed: reader.collapsed().to_vec(),
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    UnknownFormat,
+    Ase(AseError),
+    Bmp(BmpError),
+    Gif(GifError),
+    PngDecoding(DecodingError),
+    PngReading(PngReadError),
+}
+
+impl Display for ReadError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        use ReadError::*;
+
+        match self {
+            Io(err) => err.fmt(fmt),
+            UnknownFormat => write!(fmt, "Unknown image format"),
+            Ase(err) => err.fmt(fmt),
+            Bmp(err) => err.fmt(fmt),
+            Gif(err) => err.fmt(fmt),
+            PngDecoding(err) => err.fmt(fmt),
+            PngReading(err) => err.fmt(fmt),
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use ReadError::*;
+
+        match self {
+            Io(err) => Some(err),
+            UnknownFormat => None,
+            Ase(err) => Some(err),
+            Bmp(err) => Some(err),
+            Gif(err) => Some(err),
+            PngDecoding(err) => Some(err),
+            PngReading(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<AseError> for ReadError {
+    fn from(err: AseError) -> Self {
+        Self::Ase(err)
+    }
+}
+
+impl From<BmpError> for ReadError {
+    fn from(err: BmpError) -> Self {
+        Self::Bmp(err)
+    }
+}
+
+impl From<GifError> for ReadError {
+    fn from(err: GifError) -> Self {
+        Self::Gif(err)
+    }
+}
+
+impl From<DecodingError> for ReadError {
+    fn from(err: DecodingError) -> Self {
+        Self::PngDecoding(err)
+    }
+}
+
+impl From<PngReadError> for ReadError {
+    fn from(err: PngReadError) -> Self {
+        Self::PngReading(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        assert_eq!(
+            Format::detect(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"),
+            Some(Format::Png)
+        );
+        assert_eq!(Format::detect(b"BM\x3a\0\0\0"), Some(Format::Bmp));
+        assert_eq!(Format::detect(b"GIF89a\x01\0"), Some(Format::Gif));
+        assert_eq!(
+            Format::detect(b"\x80\0\0\0\xE0\xA5\x01\0"),
+            Some(Format::Ase)
+        );
+        assert_eq!(Format::detect(b"GIF90a"), None);
+        assert_eq!(Format::detect(b"\0\0\0\0\xE0"), None);
+        assert_eq!(Format::detect(b""), None);
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(matches!(
+            read(&b"JASC-PAL\r\n0100\r\n"[..], &ReadOptions::default()),
+            Err(ReadError::UnknownFormat)
+        ));
+        // Forcing a format skips detection
+        let options = ReadOptions {
+            format: Some(Format::Gif),
+            ..ReadOptions::default()
+        };
+        assert!(matches!(
+            read(&b"JASC-PAL\r\n0100\r\n"[..], &options),
+            Err(ReadError::Gif(GifError::NotGif))
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+use super::{Color, Image, ImageReader, MAX_PIXELS};
+use std::convert::{TryFrom, TryInto};
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+
+/// Reads the first frame of a GIF file.
+pub struct GifReader<R: Read> {
+    input: R,
+    width: u16,  // Of the logical screen
+    height: u16, // Of the logical screen
+    frame: Frame,
+    palette: Vec<Color>,
+}
+
+/// Where the frame lies on the logical screen
+struct Frame {
+    left: u16,
+    top: u16,
+    width: u16,
+    height: u16,
+    interlaced: bool,
+}
+
+fn u16_at(buf: &[u8], ofs: usize) -> u16 {
+    u16::from_le_bytes(buf[ofs..ofs + 2].try_into().unwrap())
+}
+
+/// Reads a color table, whose size is encoded in the 3 low bits of `flags`.
+fn read_color_table<R: Read>(input: &mut R, flags: u8) -> io::Result<Vec<Color>> {
+    let mut table = vec![0; 3 << ((flags & 7) + 1)];
+    input.read_exact(&mut table)?;
+    Ok(table
+        .chunks(3)
+        .enumerate()
+        .map(|(i, rgb)| {
+            Color::new(
+                Color::rgb_to_rgba((rgb[0], rgb[1], rgb[2])),
+                // Color tables contain at most 256 entries
+                Some(u8::try_from(i).unwrap()),
+            )
+        })
+        .collect())
+}
+
+/// Reads data sub-blocks until the terminator, concatenating them.
+fn read_sub_blocks<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let mut len = [0];
+        input.read_exact(&mut len)?;
+        if len[0] == 0 {
+            return Ok(data);
+        }
+        let start = data.len();
+        data.resize(start + usize::from(len[0]), 0);
+        input.read_exact(&mut data[start..])?;
+    }
+}
+
+impl<R: Read> ImageReader<R> for GifReader<R> {
+    type NewError = GifError;
+
+    fn new(mut input: R) -> Result<Self, Self::NewError> {
+        let mut header = [0; 13];
+        input.read_exact(&mut header)?;
+        if &header[..6] != b"GIF87a" && &header[..6] != b"GIF89a" {
+            return Err(GifError::NotGif);
+        }
+        let (width, height, flags) = (u16_at(&header, 6), u16_at(&header, 8), header[10]);
+        if usize::from(width) * usize::from(height) > MAX_PIXELS {
+            return Err(GifError::TooBig(width, height));
+        }
+        let global_table = if flags & 0x80 != 0 {
+            Some(read_color_table(&mut input, flags)?)
+        } else {
+            None
+        };
+
+        // Skip extensions until the first frame, only remembering its transparent color
+        let mut transparent = None;
+        loop {
+            let mut introducer = [0];
+            input.read_exact(&mut introducer)?;
+            match introducer[0] {
+                // Extension
+                0x21 => {
+                    let mut label = [0];
+                    input.read_exact(&mut label)?;
+                    let data = read_sub_blocks(&mut input)?;
+                    // Graphic control extension
+                    if label[0] == 0xF9 && data.len() >= 4 {
+                        transparent = if data[0] & 1 != 0 {
+                            Some(data[3])
+                        } else {
+                            None
+                        };
+                    }
+                }
+                // Image descriptor
+                0x2C => break,
+                // Trailer
+                0x3B => return Err(GifError::NoFrame),
+                byte => return Err(GifError::BadBlock(byte)),
+            }
+        }
+
+        let mut descriptor = [0; 9];
+        input.read_exact(&mut descriptor)?;
+        let flags = descriptor[8];
+        let frame = Frame {
+            left: u16_at(&descriptor, 0),
+            top: u16_at(&descriptor, 2),
+            width: u16_at(&descriptor, 4),
+            height: u16_at(&descriptor, 6),
+            interlaced: flags & 0x40 != 0,
+        };
+        if u32::from(frame.left) + u32::from(frame.width) > u32::from(width)
+            || u32::from(frame.top) + u32::from(frame.height) > u32::from(height)
+        {
+            return Err(GifError::OobFrame);
+        }
+        let mut palette = if flags & 0x80 != 0 {
+            read_color_table(&mut input, flags)?
+        } else {
+            global_table.ok_or(GifError::NoColorTable)?
+        };
+        if let Some(index) = transparent {
+            if let Some(color) = palette.get_mut(usize::from(index)) {
+                let [red, green, blue, _] = color.rgba();
+                *color = Color::new((red, green, blue, 0), Some(index));
+            }
+        }
+
+        Ok(Self {
+            input,
+            width,
+            height,
+            frame,
+            palette,
+        })
+    }
+
+    type ReadError = GifError;
+
+    fn read_image(&mut self) -> Result<Image, Self::ReadError> {
+        let mut min_code_size = [0];
+        self.input.read_exact(&mut min_code_size)?;
+        let data = read_sub_blocks(&mut self.input)?;
+        let frame = &self.frame;
+        let (frame_width, frame_height) = (usize::from(frame.width), usize::from(frame.height));
+        let indices = decode_lzw(&data, min_code_size[0], frame_width * frame_height)?;
+
+        let rows = row_order(frame_height, frame.interlaced);
+
+        let (width, height) = (usize::from(self.width), usize::from(self.height));
+        // Like browsers do, leave whatever the frame doesn't cover transparent
+        let mut pixels = vec![Color::TRANSPARENT; width * height];
+        for (row, y) in indices.chunks(frame_width.max(1)).zip(rows) {
+            let start = (usize::from(frame.top) + y) * width + usize::from(frame.left);
+            for (pixel, &index) in pixels[start..start + frame_width].iter_mut().zip(row) {
+                *pixel = self
+                    .palette
+                    .get(usize::from(index))
+                    .cloned()
+                    .ok_or(GifError::BadIndex(index))?;
+            }
+        }
+
+        Ok(Image::new(self.width.into(), self.height.into(), pixels))
+    }
+}
+
+impl<R: Read> GifReader<R> {
+    /// Returns the frame's color table, with transparency applied; each color carries its index.
+    pub fn palette(&self) -> Option<Vec<Color>> {
+        Some(self.palette.clone())
+    }
+}
+
+/// Returns which row each row of the frame data is.
+fn row_order(height: usize, interlaced: bool) -> Vec<usize> {
+    if interlaced {
+        // Every 8th row starting from 0, then from 4, then every 4th starting from 2, then every
+        // other one starting from 1
+        (0..height)
+            .step_by(8)
+            .chain((4..height).step_by(8))
+            .chain((2..height).step_by(4))
+            .chain((1..height).step_by(2))
+            .collect()
+    } else {
+        (0..height).collect()
+    }
+}
+
+/// Decompresses LZW-encoded indices, expecting exactly `nb_pixels` of them.
+fn decode_lzw(data: &[u8], min_code_size: u8, nb_pixels: usize) -> Result<Vec<u8>, GifError> {
+    if !(2..=8).contains(&min_code_size) {
+        return Err(GifError::BadCodeSize(min_code_size));
+    }
+    let clear = 1 << min_code_size;
+    let end = clear + 1;
+    let reset = |table: &mut Vec<Vec<u8>>| {
+        table.clear();
+        table.extend((0..clear).map(|index| vec![index as u8]));
+        table.extend([vec![], vec![]]); // The clear and end codes
+    };
+
+    let mut table = Vec::with_capacity(4096);
+    reset(&mut table);
+    let mut code_size = min_code_size + 1;
+    let mut prev: Option<usize> = None;
+    let mut indices = Vec::new();
+    // Codes are packed least significant bit first
+    let (mut bits, mut nb_bits, mut bytes) = (0u32, 0, data.iter());
+
+    loop {
+        while nb_bits < code_size {
+            match bytes.next() {
+                Some(&byte) => {
+                    bits |= u32::from(byte) << nb_bits;
+                    nb_bits += 8;
+                }
+                // Some encoders omit the end code
+                None => return finish(indices, nb_pixels),
+            }
+        }
+        let code = usize::try_from(bits & ((1 << code_size) - 1)).unwrap();
+        bits >>= code_size;
+        nb_bits -= code_size;
+
+        if code == clear {
+            reset(&mut table);
+            code_size = min_code_size + 1;
+            prev = None;
+            continue;
+        }
+        if code == end {
+            return finish(indices, nb_pixels);
+        }
+
+        let entry = match (table.get(code), prev) {
+            (Some(entry), _) => entry.clone(),
+            // The code being defined right now: the previous entry, plus its own first index
+            (None, Some(prev)) if code == table.len() => {
+                let mut entry = table[prev].clone();
+                entry.push(entry[0]);
+                entry
+            }
+            _ => return Err(GifError::BadCode(code)),
+        };
+        if entry.is_empty() {
+            return Err(GifError::BadCode(code));
+        }
+        indices.extend_from_slice(&entry);
+
+        if let Some(prev) = prev {
+            if table.len() < 4096 {
+                let mut new = table[prev].clone();
+                new.push(entry[0]);
+                table.push(new);
+                if table.len() == 1 << code_size && code_size < 12 {
+                    code_size += 1;
+                }
+            }
+        }
+        prev = Some(code);
+    }
+}
+
+fn finish(mut indices: Vec<u8>, nb_pixels: usize) -> Result<Vec<u8>, GifError> {
+    if indices.len() < nb_pixels {
+        return Err(GifError::Truncated);
+    }
+    indices.truncate(nb_pixels);
+    Ok(indices)
+}
+
+#[derive(Debug)]
+pub enum GifError {
+    Io(io::Error),
+    NotGif,
+    BadBlock(u8),
+    BadCode(usize),
+    BadCodeSize(u8),
+    BadIndex(u8),
+    NoColorTable,
+    NoFrame,
+    OobFrame,
+    TooBig(u16, u16), // width, height
+    Truncated,
+}
+
+impl Display for GifError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        use GifError::*;
+
+        match self {
+            Io(err) => err.fmt(fmt),
+            NotGif => write!(fmt, "Not a GIF file"),
+            BadBlock(byte) => write!(fmt, "Unknown GIF block type ${:02X}", byte),
+            BadCode(code) => write!(fmt, "Invalid LZW code {} in GIF image data", code),
+            BadCodeSize(size) => write!(fmt, "Invalid LZW code size {} in GIF image data", size),
+            BadIndex(index) => write!(fmt, "Color index {} is not in the color table", index),
+            NoColorTable => write!(fmt, "GIF frame has no color table"),
+            NoFrame => write!(fmt, "GIF file contains no image"),
+            OobFrame => write!(fmt, "GIF frame is not within the image's bounds"),
+            TooBig(w, h) => write!(fmt, "Image too big! ({} px wide, {} px tall)", w, h),
+            Truncated => write!(fmt, "GIF image data is truncated"),
+        }
+    }
+}
+
+impl error::Error for GifError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            GifError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GifError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs codes LSB first, as GIF does.
+    fn pack(codes: &[(u16, u8)]) -> Vec<u8> {
+        let (mut bytes, mut bits, mut nb_bits) = (Vec::new(), 0u32, 0);
+        for &(code, size) in codes {
+            bits |= u32::from(code) << nb_bits;
+            nb_bits += size;
+            while nb_bits >= 8 {
+                bytes.push(bits as u8);
+                bits >>= 8;
+                nb_bits -= 8;
+            }
+        }
+        if nb_bits != 0 {
+            bytes.push(bits as u8);
+        }
+        bytes
+    }
+
+    /// A 4x2 GIF with a 4-color global table, where index 3 is transparent.
+    fn gif() -> Vec<u8> {
+        let mut file = b"GIF89a".to_vec();
+        file.extend_from_slice(&[4, 0, 2, 0, 0x81, 0, 0]);
+        file.extend_from_slice(&[0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 255, 0]);
+        file.extend_from_slice(&[0x21, 0xF9, 4, 1, 0, 0, 3, 0]);
+        file.extend_from_slice(&[0x2C, 0, 0, 0, 0, 4, 0, 2, 0, 0]);
+        // Indices 1 1 1 1 / 2 3 2 3: clear, 1, 6 (1 1), 1, 2, 3, 9 (2 3), end
+        let data = pack(&[
+            (4, 3),
+            (1, 3),
+            (6, 3),
+            (1, 3),
+            (2, 4),
+            (3, 4),
+            (9, 4),
+            (5, 4),
+        ]);
+        file.push(2);
+        file.push(data.len() as u8);
+        file.extend_from_slice(&data);
+        file.extend_from_slice(&[0, 0x3B]);
+        file
+    }
+
+    #[test]
+    fn lzw() {
+        let data = pack(&[
+            (4, 3),
+            (1, 3),
+            (6, 3),
+            (1, 3),
+            (2, 4),
+            (3, 4),
+            (9, 4),
+            (5, 4),
+        ]);
+        assert_eq!(decode_lzw(&data, 2, 8).unwrap(), [1, 1, 1, 1, 2, 3, 2, 3]);
+        assert!(matches!(decode_lzw(&data, 2, 9), Err(GifError::Truncated)));
+    }
+
+    #[test]
+    fn read() {
+        let file = gif();
+        let mut reader = GifReader::new(&file[..]).unwrap();
+        let palette = reader.palette().unwrap();
+        let img = reader.read_image().unwrap();
+        assert_eq!(img.pixels()[0], Color::new((255, 255, 255, 255), Some(1)));
+        // Transparent pixels don't keep their index
+        assert_eq!(img.pixels()[5], Color::TRANSPARENT);
+        assert_eq!(img.pixels()[5], palette[3]);
+        assert_eq!(img.pixels()[4].palette_index(), Some(2));
+    }
+
+    #[test]
+    fn interlaced() {
+        assert_eq!(row_order(10, true), [0, 8, 4, 2, 6, 1, 3, 5, 7, 9]);
+        assert_eq!(row_order(3, false), [0, 1, 2]);
+    }
+
+    #[test]
+    fn not_gif() {
+        assert!(matches!(
+            GifReader::new(&b"BM\0\0\0\0\0\0\0\0\0\0\0\0"[..]),
+            Err(GifError::NotGif)
+        ));
+    }
+
+    #[test]
+    fn too_big() {
+        let mut file = gif();
+        file[6..10].copy_from_slice(&[0xFF; 4]);
+        assert!(matches!(
+            GifReader::new(&file[..]),
+            Err(GifError::TooBig(65535, 65535))
+        ));
+    }
+}
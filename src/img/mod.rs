@@ -1,17 +1,30 @@
+mod ase;
+pub use ase::{AseError, AseFrames, AseOptions, AseReader};
+mod bmp;
+pub use bmp::{BmpError, BmpReader};
+mod curve;
+pub use curve::Curve;
+mod format;
+pub use format::{read, Decoded, Format, ReadError, ReadOptions};
+mod gif;
+pub use gif::{GifError, GifReader};
 mod png;
-pub use self::png::{PngReadError, PngReader};
+pub use self::png::{Collapsed, DeepColor, PngOptions, PngReadError, PngReader};
+mod rounding;
+pub use rounding::Rounding;
 
 use std::io::Read;
 use std::ops::Index;
 
 pub use color::Color;
 mod color {
+    use super::Rounding;
     use std::fmt::{self, Display, Formatter, LowerHex, UpperHex};
 
     // Implementing `PartialEq` in this way makes identical colors with a different palette index
     // different. This is intentional, so that a palette may contain duplicates of a given color
     // if the user insists on it (either via a PNG palette, or a CLI specification)
-    #[derive(Debug, PartialEq, Eq, Clone)]
+    #[derive(Debug, PartialEq, Eq, Hash, Clone)]
     pub struct Color {
         red: u8,
         green: u8,
@@ -21,7 +34,30 @@ mod color {
     }
 
     impl Color {
+        /// The color used to pad unused palette entries (magenta, since it's rarely used)
+        pub const FILLER: Color = Color {
+            red: 0xFF,
+            green: 0x00,
+            blue: 0xFF,
+            alpha: 0xFF,
+            palette_index: None,
+        };
+
+        /// What all fully transparent colors are normalised to
+        pub const TRANSPARENT: Color = Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 0,
+            palette_index: None,
+        };
+
+        /// Fully transparent colors all look the same, so they are all normalised to
+        /// `TRANSPARENT`, even dropping their palette index.
         pub fn new((red, green, blue, alpha): (u8, u8, u8, u8), index: Option<u8>) -> Self {
+            if alpha == 0 {
+                return Self::TRANSPARENT;
+            }
             Self {
                 red,
                 green,
@@ -35,19 +71,33 @@ mod color {
             [self.red, self.green, self.blue, self.alpha]
         }
 
+        pub fn palette_index(&self) -> Option<u8> {
+            self.palette_index
+        }
+
+        /// Checks if a palette's color can stand for this one.
+        /// Unlike `==`, palette indexes are only compared if both colors have one; this allows
+        /// e.g. textual palette specs to be used with indexed images.
+        pub fn matches(&self, other: &Color) -> bool {
+            match (self.palette_index, other.palette_index) {
+                (Some(_), Some(_)) => self == other,
+                _ => self.rgba() == other.rgba(),
+            }
+        }
+
+        /// Channels are scaled up with `Rounding::expand`, so that any `Rounding` converts them
+        /// back as-is.
         pub fn from_rgb555(color: u16, index: Option<u8>) -> Self {
+            let expand = |channel: u16| Rounding::expand(channel as u8);
             Self::new(
-                Self::rgb_to_rgba((
-                    color as u8 & 0x1F,
-                    (color >> 5) as u8 & 0x1F,
-                    (color >> 10) as u8 & 0x1F,
-                )),
+                Self::rgb_to_rgba((expand(color), expand(color >> 5), expand(color >> 10))),
                 index,
             )
         }
 
-        pub fn to_rgb555(&self) -> u16 {
-            u16::from(self.red) | u16::from(self.green) << 5 | u16::from(self.blue) << 10
+        pub fn to_rgb555(&self, rounding: Rounding) -> u16 {
+            let quantize = |channel| u16::from(rounding.quantize(channel));
+            quantize(self.red) | quantize(self.green) << 5 | quantize(self.blue) << 10
         }
 
         pub fn rgb_to_rgba((red, green, blue): (u8, u8, u8)) -> (u8, u8, u8, u8) {
@@ -122,6 +172,10 @@ mod color {
     }
 }
 
+/// How many pixels an image may have at most, so that bogus dimensions in a file's header can't
+/// exhaust memory; 8192x8192 is still far more than the Game Boy could ever use.
+pub const MAX_PIXELS: usize = 1 << 26;
+
 #[derive(Debug)]
 pub struct Image {
     width: u32,  // Size in pixels
@@ -130,6 +184,15 @@ pub struct Image {
 }
 
 impl Image {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
@@ -137,6 +200,15 @@ impl Image {
     pub fn height(&self) -> u32 {
         self.height
     }
+
+    /// The image's pixels, row by row
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        &mut self.pixels
+    }
 }
 
 impl Index<(u32, u32)> for Image {
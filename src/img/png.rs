@@ -1,13 +1,53 @@
-use super::{Color, Image, ImageReader};
-use png::{ColorType, Decoder, DecodingError, Reader, Transformations};
+use super::{Color, Image, ImageReader, Rounding};
+use png::{BitDepth, ColorType, Decoder, DecodingError, Reader, Transformations};
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::error;
 use std::fmt::Display;
 use std::fmt::{self, Formatter};
 use std::io::Read;
 
+/// How 16-bit samples are brought down to 8 bits
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum DeepColor {
+    /// Round to the nearest 8-bit value
+    #[default]
+    Round,
+    /// Round color channels straight to 5 bits, which the 8-bit value then converts back to
+    Rgb555(Rounding),
+}
+
+impl DeepColor {
+    fn channel(self, sample: u16) -> u8 {
+        match self {
+            DeepColor::Round => round16(sample),
+            DeepColor::Rgb555(rounding) => Rounding::expand(rounding.quantize16(sample)),
+        }
+    }
+}
+
+fn round16(sample: u16) -> u8 {
+    ((u32::from(sample) * 255 + 32767) / 65535) as u8
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PngOptions {
+    pub deep_color: DeepColor,
+    pub find_collapsed: bool, // Look for distinct 16-bit colors that end up as the same one
+    pub rounding: Rounding,   // How colors are converted to RGB555 afterwards
+}
+
+/// Distinct 16-bit colors that all end up as the same RGB555 color
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Collapsed {
+    pub rgb555: u16,
+    pub sources: Vec<[u16; 4]>, // RGBA, sorted
+}
+
 pub struct PngReader<R: Read> {
     reader: Reader<R>,
+    options: PngOptions,
+    collapsed: Vec<Collapsed>,
 }
 
 impl<R: Read> ImageReader<R> for PngReader<R> {
@@ -17,7 +57,11 @@ impl<R: Read> ImageReader<R> for PngReader<R> {
         let mut decoder = Decoder::new(input);
         decoder.set_transformations(Transformations::IDENTITY);
         let (_, reader) = decoder.read_info()?;
-        Ok(Self { reader })
+        Ok(Self {
+            reader,
+            options: PngOptions::default(),
+            collapsed: Vec::new(),
+        })
     }
 
     type ReadError = PngReadError;
@@ -27,38 +71,13 @@ impl<R: Read> ImageReader<R> for PngReader<R> {
         let (width, height, color_type, bit_depth) =
             (info.width, info.height, info.color_type, info.bit_depth);
 
-        // Compute the palette, if any.
-        // `info.palette` contains the PLTE chunk, which contains RGB888 entries, if present.
-        // `info.trns` contains the tRNS chunks, which contains 8-bit alpha entries, if present.
-        let palette = info.palette.as_ref().map(|buf| {
-            assert_eq!(buf.len() % 3, 0);
-            let nb_colors = buf.len() / 3;
-            let mut palette = Vec::with_capacity(nb_colors);
-            let mut it = buf.iter();
-            for i in 0..nb_colors {
-                let (r, g, b) = (
-                    *it.next().unwrap(),
-                    *it.next().unwrap(),
-                    *it.next().unwrap(),
-                );
-                palette.push(
-                    info.trns
-                        .as_ref()
-                        // The tRNS chunk shall not contain more alpha values than there are palette
-                        // entries, but a tRNS chunk may contain fewer values than there are palette
-                        // entries. In this case, the alpha value for all remaining palette entries
-                        // is assumed to be 255.
-                        .and_then(|trns| trns.get(i))
-                        .map_or_else(|| Color::rgb_to_rgba((r, g, b)), |alpha| (r, g, b, *alpha)),
-                );
-            }
-            palette
-        });
+        let palette = self.palette();
 
         let nb_pixels: usize = width
             .checked_mul(height)
-            .map(|size| size.try_into().ok()) // We don't care about the actual error
-            .flatten() // Return the same error whether the multiplication or conversion failed
+            // We don't care about the actual error, so return the same one whether the
+            // multiplication or the conversion failed
+            .and_then(|size| size.try_into().ok())
             .ok_or(PngReadError::TooBig(width, height))?;
 
         let mut data = vec![0; info.raw_bytes()];
@@ -67,58 +86,158 @@ impl<R: Read> ImageReader<R> for PngReader<R> {
             .map_err(PngReadError::DecodingError)?;
         let mut samples = SampleIterator::new(&data, bit_depth, width);
 
+        let deep = bit_depth == BitDepth::Sixteen;
+        let deep_color = self.options.deep_color;
+        // Samples of other depths are all 8-bit or less
+        let channel = |sample: u16| {
+            if deep {
+                deep_color.channel(sample)
+            } else {
+                sample as u8
+            }
+        };
+        let alpha = |sample: u16| if deep { round16(sample) } else { sample as u8 };
+        // Which 16-bit colors each RGB555 color (and alpha) comes from
+        let mut sources: HashMap<(u16, u8), HashSet<[u16; 4]>> = HashMap::new();
+        let find_collapsed = deep && self.options.find_collapsed;
+
         // Write pixels from raw data
         let mut pixels = Vec::with_capacity(nb_pixels);
         for _ in 0..nb_pixels {
-            pixels.push(match color_type {
+            let (color, source) = match color_type {
                 ColorType::Grayscale => {
                     assert_eq!(color_type.samples(), 1);
-                    Color::new(
-                        Color::rgb_to_rgba(Color::gray_to_rgb(samples.next().unwrap())),
-                        None,
+                    let gray = samples.next().unwrap();
+                    (
+                        Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(channel(gray))), None),
+                        [gray, gray, gray, 0xFFFF],
                     )
                 }
                 ColorType::RGB => {
                     assert_eq!(color_type.samples(), 3);
-                    Color::new(
-                        Color::rgb_to_rgba((
-                            samples.next().unwrap(),
-                            samples.next().unwrap(),
-                            samples.next().unwrap(),
-                        )),
-                        None,
+                    let rgb = [
+                        samples.next().unwrap(),
+                        samples.next().unwrap(),
+                        samples.next().unwrap(),
+                    ];
+                    (
+                        Color::new(
+                            Color::rgb_to_rgba((channel(rgb[0]), channel(rgb[1]), channel(rgb[2]))),
+                            None,
+                        ),
+                        [rgb[0], rgb[1], rgb[2], 0xFFFF],
                     )
                 }
                 ColorType::Indexed => {
                     assert_eq!(color_type.samples(), 1);
+                    // Section 11.2.2 of the PNG standard disallows 16-bit indexed images
                     let index = samples.next().unwrap();
                     let palette = palette.as_ref().unwrap();
-                    Color::new(palette[usize::try_from(index).unwrap()], Some(index))
+                    (palette[usize::from(index)].clone(), [0; 4])
                 }
                 ColorType::GrayscaleAlpha => {
                     assert_eq!(color_type.samples(), 2);
-                    let rgb = Color::gray_to_rgb(samples.next().unwrap());
-                    Color::new((rgb.0, rgb.1, rgb.2, samples.next().unwrap()), None)
+                    let (gray, opacity) = (samples.next().unwrap(), samples.next().unwrap());
+                    let rgb = Color::gray_to_rgb(channel(gray));
+                    (
+                        Color::new((rgb.0, rgb.1, rgb.2, alpha(opacity)), None),
+                        [gray, gray, gray, opacity],
+                    )
                 }
                 ColorType::RGBA => {
                     assert_eq!(color_type.samples(), 4);
-                    Color::new(
-                        (
-                            samples.next().unwrap(),
-                            samples.next().unwrap(),
-                            samples.next().unwrap(),
-                            samples.next().unwrap(),
+                    let rgba = [
+                        samples.next().unwrap(),
+                        samples.next().unwrap(),
+                        samples.next().unwrap(),
+                        samples.next().unwrap(),
+                    ];
+                    (
+                        Color::new(
+                            (
+                                channel(rgba[0]),
+                                channel(rgba[1]),
+                                channel(rgba[2]),
+                                alpha(rgba[3]),
+                            ),
+                            None,
                         ),
-                        None,
+                        rgba,
                     )
                 }
-            });
+            };
+            // Fully transparent colors all look the same anyway
+            if find_collapsed && color.rgba()[3] != 0 {
+                sources
+                    .entry((color.to_rgb555(self.options.rounding), color.rgba()[3]))
+                    .or_default()
+                    .insert(source);
+            }
+            pixels.push(color);
         }
 
-        Ok(Image {
-            width,
-            height,
-            pixels,
+        // Colors that are the same with 8 bits per channel would have collapsed anyway, so only
+        // report groups where the extra precision is actually lost
+        let mut collapsed: Vec<_> = sources
+            .into_iter()
+            .filter(|(_, sources)| {
+                let rounded: HashSet<_> = sources.iter().map(|rgba| rgba.map(round16)).collect();
+                rounded.len() > 1
+            })
+            .map(|((rgb555, _), sources)| {
+                let mut sources: Vec<_> = sources.into_iter().collect();
+                sources.sort_unstable();
+                Collapsed { rgb555, sources }
+            })
+            .collect();
+        collapsed.sort_unstable();
+        self.collapsed = collapsed;
+
+        Ok(Image::new(width, height, pixels))
+    }
+}
+
+impl<R: Read> PngReader<R> {
+    pub fn set_options(&mut self, options: PngOptions) {
+        self.options = options;
+    }
+
+    /// Returns the distinct 16-bit colors that `read_image` read as the same RGB555 color, if asked
+    /// to look for them.
+    pub fn collapsed(&self) -> &[Collapsed] {
+        &self.collapsed
+    }
+
+    /// Returns the image's palette (PLTE chunk, completed by the tRNS chunk), if any.
+    /// Each color carries its index within the palette.
+    pub fn palette(&self) -> Option<Vec<Color>> {
+        let info = self.reader.info();
+
+        // `info.palette` contains the PLTE chunk, which contains RGB888 entries, if present.
+        // `info.trns` contains the tRNS chunks, which contains 8-bit alpha entries, if present.
+        info.palette.as_ref().map(|buf| {
+            assert_eq!(buf.len() % 3, 0);
+            buf.chunks(3)
+                .enumerate()
+                .map(|(i, rgb)| {
+                    let (r, g, b) = (rgb[0], rgb[1], rgb[2]);
+                    Color::new(
+                        info.trns
+                            .as_ref()
+                            // The tRNS chunk shall not contain more alpha values than there are palette
+                            // entries, but a tRNS chunk may contain fewer values than there are palette
+                            // entries. In this case, the alpha value for all remaining palette entries
+                            // is assumed to be 255.
+                            .and_then(|trns| trns.get(i))
+                            .map_or_else(
+                                || Color::rgb_to_rgba((r, g, b)),
+                                |alpha| (r, g, b, *alpha),
+                            ),
+                        // The PLTE chunk contains at most 256 entries
+                        Some(u8::try_from(i).unwrap()),
+                    )
+                })
+                .collect()
         })
     }
 }
@@ -156,7 +275,7 @@ mod iter {
     // (Try a 1-bit colormap with less than 8 pixels, you'll see)
     // We don't attempt to handle those cases, since we require images to be at least 8 pixels
     impl Iterator for SampleIterator<'_> {
-        type Item = u8;
+        type Item = u16;
 
         fn next(&mut self) -> Option<<Self as Iterator>::Item> {
             if self.index == self.buf.len() {
@@ -165,19 +284,18 @@ mod iter {
                 use BitDepth::*;
 
                 Some(if self.bit_depth == Sixteen {
-                    // 16-bit needs special handling.
-                    // Its entries are larger than `u8`, so we perform truncation;
-                    // such precision isn't needed for a Game Boy game, anyway.
-                    // There is one exception, which is palette indexes...
-                    // However, Section 11.2.2 of the PNG standard disallows several
-                    // combinations of bit depth and color type, including this one!
-                    let val = self.buf[self.index]; // `png` returns big-endian bytes
+                    // `png` returns big-endian bytes
+                    let high = self.buf[self.index];
                     self.index += 1;
                     // Check for an odd-sized buffer; it would be incorrect, but let's be lenient
-                    if self.index != self.buf.len() {
-                        self.index += 1; // Ignore the second byte
-                    }
-                    val
+                    let low = match self.buf.get(self.index) {
+                        Some(&low) => {
+                            self.index += 1;
+                            low
+                        }
+                        None => 0,
+                    };
+                    u16::from_be_bytes([high, low])
                 } else {
                     let len = match self.bit_depth {
                         One => 1,
@@ -203,7 +321,7 @@ mod iter {
                         self.shift = 8;
                     }
 
-                    val
+                    u16::from(val)
                 })
             }
         }
@@ -237,3 +355,151 @@ impl error::Error for PngReadError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        // CRC-32 of the type and data
+        let mut crc = !0u32;
+        for &byte in &png[start..] {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    crc >> 1 ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        png.extend_from_slice(&(!crc).to_be_bytes());
+    }
+
+    /// A single-row, 16-bit RGB or RGBA image
+    fn png16<const N: usize>(pixels: &[[u16; N]]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = (pixels.len() as u32).to_be_bytes().to_vec();
+        let color_type = if N == 4 { 6 } else { 2 };
+        header.extend_from_slice(&[0, 0, 0, 1, 16, color_type, 0, 0, 0]);
+        chunk(&mut png, b"IHDR", &header);
+        let mut row = vec![0]; // No filtering
+        for pixel in pixels {
+            for channel in pixel {
+                row.extend_from_slice(&channel.to_be_bytes());
+            }
+        }
+        chunk(
+            &mut png,
+            b"IDAT",
+            &miniz_oxide::deflate::compress_to_vec_zlib(&row, 6),
+        );
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn transparent_entries() {
+        // Indices 0 1 2, the first two of which are transparent
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunk(&mut png, b"IHDR", &[0, 0, 0, 3, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+        chunk(&mut png, b"PLTE", &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        chunk(&mut png, b"tRNS", &[0, 0]);
+        chunk(
+            &mut png,
+            b"IDAT",
+            &miniz_oxide::deflate::compress_to_vec_zlib(&[0, 0, 1, 2], 6),
+        );
+        chunk(&mut png, b"IEND", &[]);
+
+        let mut reader = PngReader::new(&png[..]).unwrap();
+        let palette = reader.palette().unwrap();
+        let img = reader.read_image().unwrap();
+        // They are the same color, whichever entry they come from
+        assert_eq!(palette[0], palette[1]);
+        assert_eq!(img.pixels()[0], Color::TRANSPARENT);
+        assert_eq!(img.pixels()[1], Color::TRANSPARENT);
+        assert_eq!(img.pixels()[2], Color::new((7, 8, 9, 255), Some(2)));
+    }
+
+    #[test]
+    fn deep_channels() {
+        assert_eq!(round16(0), 0);
+        assert_eq!(round16(0xFFFF), 255);
+        assert_eq!(round16(0x807F), 128);
+        assert_eq!(round16(0x8080), 128);
+        assert_eq!(round16(0x8100), 128);
+        assert_eq!(round16(0x8101), 129);
+        // 0x8000 is closer to 16/31 than 15/31
+        assert_eq!(DeepColor::Rgb555(Rounding::Nearest).channel(0x8000), 0x84);
+        assert_eq!(DeepColor::Rgb555(Rounding::Truncate).channel(0x8000), 0x84);
+        assert_eq!(DeepColor::Rgb555(Rounding::Scale).channel(0x8000), 0x7C);
+    }
+
+    #[test]
+    fn collapsed() {
+        let png = png16(&[
+            [0x8000, 0, 0xFFFF],
+            [0x80FF, 0, 0xFFFF],
+            [0x8181, 0, 0xFFFF],
+            [0x8888, 0, 0xFFFF],
+        ]);
+        let mut reader = PngReader::new(&png[..]).unwrap();
+        reader.set_options(PngOptions {
+            find_collapsed: true,
+            ..PngOptions::default()
+        });
+        let img = reader.read_image().unwrap();
+        let reds: Vec<_> = img.pixels().iter().map(|color| color.rgba()[0]).collect();
+        assert_eq!(reds, [128, 128, 129, 136]);
+        // 128 and 129 are distinct, but both become the same RGB555 color
+        assert_eq!(
+            reader.collapsed(),
+            [Collapsed {
+                rgb555: 0x7C10,
+                sources: vec![
+                    [0x8000, 0, 0xFFFF, 0xFFFF],
+                    [0x80FF, 0, 0xFFFF, 0xFFFF],
+                    [0x8181, 0, 0xFFFF, 0xFFFF]
+                ],
+            }]
+        );
+
+        // Straight to RGB555, the same ones are grouped
+        let mut reader = PngReader::new(&png[..]).unwrap();
+        reader.set_options(PngOptions {
+            deep_color: DeepColor::Rgb555(Rounding::Nearest),
+            find_collapsed: true,
+            rounding: Rounding::Nearest,
+        });
+        reader.read_image().unwrap();
+        assert_eq!(reader.collapsed().len(), 1);
+        assert_eq!(reader.collapsed()[0].sources.len(), 3);
+    }
+
+    #[test]
+    fn collapsed_only_if_lost() {
+        let read = |png: &[u8]| {
+            let mut reader = PngReader::new(png).unwrap();
+            reader.set_options(PngOptions {
+                find_collapsed: true,
+                ..PngOptions::default()
+            });
+            reader.read_image().unwrap();
+            reader.collapsed().to_vec()
+        };
+        // A gradient that doesn't even survive 8 bits per channel
+        let png = png16(&[[0x8000, 0, 0], [0x8040, 0, 0], [0x8080, 0, 0]]);
+        assert_eq!(read(&png), []);
+
+        // Different opacities are different colors
+        let png = png16(&[[0x8000, 0, 0, 0xFFFF], [0x8181, 0, 0, 0x8000]]);
+        assert_eq!(read(&png), []);
+        let png = png16(&[[0x8000, 0, 0, 0x8000], [0x8181, 0, 0, 0x8000]]);
+        assert_eq!(read(&png).len(), 1);
+    }
+}
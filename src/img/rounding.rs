@@ -0,0 +1,123 @@
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Ways of converting 8-bit color channels down to 5 bits
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Rounding {
+    /// Drop the 3 low bits
+    Truncate,
+    /// Pick the 5-bit value whose 8-bit expansion is closest
+    #[default]
+    Nearest,
+    /// `x * 31 / 255`, rounding down
+    Scale,
+}
+
+impl Rounding {
+    pub fn quantize(self, channel: u8) -> u8 {
+        let channel = u16::from(channel);
+        let quantized = match self {
+            Rounding::Truncate => channel >> 3,
+            Rounding::Nearest => (channel * 31 + 127) / 255,
+            Rounding::Scale => channel * 31 / 255,
+        };
+        quantized as u8
+    }
+
+    /// Same as `quantize`, but for 16-bit channels
+    pub fn quantize16(self, channel: u16) -> u8 {
+        let channel = u32::from(channel);
+        let quantized = match self {
+            Rounding::Truncate => channel >> 11,
+            Rounding::Nearest => (channel * 31 + 32767) / 65535,
+            Rounding::Scale => channel * 31 / 65535,
+        };
+        quantized as u8
+    }
+
+    /// Scales a 5-bit channel up to 8 bits, rounding up, so that any `Rounding` converts it back
+    /// as-is.
+    pub fn expand(channel: u8) -> u8 {
+        (u16::from(channel & 0x1F) * 255).div_ceil(31) as u8
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = UnknownRounding;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "truncate" => Ok(Rounding::Truncate),
+            "nearest" => Ok(Rounding::Nearest),
+            "scale" => Ok(Rounding::Scale),
+            _ => Err(UnknownRounding(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownRounding(String);
+
+impl Display for UnknownRounding {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Unknown rounding \"{}\"", self.0)
+    }
+}
+
+impl error::Error for UnknownRounding {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Color;
+    use super::*;
+
+    const ALL: [Rounding; 3] = [Rounding::Truncate, Rounding::Nearest, Rounding::Scale];
+
+    #[test]
+    fn rgb555_round_trips() {
+        for &rounding in &ALL {
+            for color in 0..0x8000 {
+                assert_eq!(
+                    Color::from_rgb555(color, None).to_rgb555(rounding),
+                    color,
+                    "{:?}",
+                    rounding
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn quantization() {
+        for &rounding in &ALL {
+            assert_eq!(rounding.quantize(0), 0);
+            assert_eq!(rounding.quantize(255), 31);
+        }
+        assert_eq!(Rounding::Truncate.quantize(0x87), 0x10);
+        assert_eq!(Rounding::Nearest.quantize(0x87), 0x10);
+        assert_eq!(Rounding::Scale.quantize(0x80), 0x0F);
+        assert_eq!(Rounding::Truncate.quantize(0x7F), 0x0F);
+        assert_eq!(Rounding::Nearest.quantize(0x7F), 0x0F);
+        assert_eq!(Rounding::Nearest.quantize(0x80), 0x10);
+    }
+
+    #[test]
+    fn quantization16() {
+        for &rounding in &ALL {
+            assert_eq!(rounding.quantize16(0), 0);
+            assert_eq!(rounding.quantize16(0xFFFF), 31);
+            // 16-bit channels that are exact 8-bit ones quantize the same way
+            for channel in 0..=255 {
+                assert_eq!(
+                    rounding.quantize16(u16::from(channel) * 0x101),
+                    rounding.quantize(channel),
+                    "{:?}",
+                    rounding
+                );
+            }
+        }
+        assert_eq!(Rounding::Nearest.quantize16(0x7FFF), 0x0F);
+        assert_eq!(Rounding::Nearest.quantize16(0x8000), 0x10);
+    }
+}
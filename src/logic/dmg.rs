@@ -0,0 +1,124 @@
+use super::ProcessingError;
+use crate::img::{Color, Image};
+use std::cmp::Reverse;
+
+/// Generates the palette that displays the image correctly once `bgp` is loaded, by ordering
+/// the image's colors by luminance and matching them to the shades `bgp` provides.
+/// All colors of the same luminance are merged into one, since they will look the same anyway.
+pub fn bgp_palette(
+    img: &mut Image,
+    bgp: u8,
+    bpp: u8,
+) -> Result<[Color; 4], ProcessingError<'static>> {
+    // The shade each color index is displayed as (0 being the lightest)
+    let shades: Vec<u8> = (0..1 << bpp).map(|i| bgp >> (i * 2) & 3).collect();
+    let mut available = shades.clone();
+    available.sort_unstable();
+    available.dedup();
+
+    let luma = |color: &Color| color.luma_chroma().0.round() as u8;
+    // The first color seen with each luminance stands for all of them
+    let mut levels: Vec<(u8, &Color)> = Vec::with_capacity(available.len());
+    let mut seen = [false; 256];
+    for pixel in img.pixels() {
+        let level = luma(pixel);
+        if !seen[usize::from(level)] {
+            seen[usize::from(level)] = true;
+            levels.push((level, pixel));
+        }
+    }
+    if levels.len() > available.len() {
+        return Err(ProcessingError::TooManyLuminances(
+            levels.len(),
+            bgp,
+            available.len(),
+        ));
+    }
+
+    // Brightest first, since shade 0 is the lightest; spread the levels over the shades
+    levels.sort_by_key(|&(level, _)| Reverse(level));
+    let mut palette = [Color::FILLER, Color::FILLER, Color::FILLER, Color::FILLER];
+    let nb_levels = levels.len();
+    for (rank, (level, color)) in levels.iter().enumerate() {
+        let shade = if nb_levels == 1 {
+            // Pick the shade closest to the color's (the shades being roughly 255, 170, 85, 0)
+            *available
+                .iter()
+                .min_by_key(|&&shade| (i16::from(*level) - (255 - i16::from(shade) * 85)).abs())
+                .unwrap()
+        } else {
+            let span = nb_levels - 1;
+            available[(rank * (available.len() - 1) * 2 + span) / (span * 2)]
+        };
+        let index = shades.iter().position(|&other| other == shade).unwrap();
+        palette[index] = (*color).clone();
+    }
+
+    // Replace all colors by their level's representative
+    let representatives: Vec<_> = levels
+        .into_iter()
+        .map(|(level, color)| (level, color.clone()))
+        .collect();
+    for pixel in img.pixels_mut() {
+        let level = luma(pixel);
+        let (_, color) = representatives
+            .iter()
+            .find(|(other, _)| *other == level)
+            .unwrap();
+        if color != pixel {
+            *pixel = color.clone();
+        }
+    }
+
+    Ok(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(shade: u8) -> Color {
+        Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), None)
+    }
+
+    fn image(shades: &[u8]) -> Image {
+        Image::new(
+            shades.len() as u32,
+            1,
+            shades.iter().map(|&shade| gray(shade)).collect(),
+        )
+    }
+
+    #[test]
+    fn identity_bgp() {
+        let mut img = image(&[0, 255, 100, 200]);
+        let palette = bgp_palette(&mut img, 0xE4, 2).unwrap();
+        assert_eq!(palette, [gray(255), gray(200), gray(100), gray(0)]);
+    }
+
+    #[test]
+    fn reversed_bgp() {
+        let mut img = image(&[0, 255]);
+        let palette = bgp_palette(&mut img, 0x1B, 2).unwrap();
+        assert_eq!(palette[0], gray(0));
+        assert_eq!(palette[3], gray(255));
+    }
+
+    #[test]
+    fn same_luminance_is_merged() {
+        let red = Color::new((255, 0, 0, 255), None);
+        let luma = red.luma_chroma().0.round() as u8;
+        let mut img = image(&[luma, luma]);
+        img.pixels_mut()[0] = red.clone();
+        bgp_palette(&mut img, 0xE4, 2).unwrap();
+        assert_eq!(img.pixels()[1], red);
+    }
+
+    #[test]
+    fn too_many_luminances() {
+        let mut img = image(&[0, 100, 255]);
+        // Only two shades available: white and black
+        assert!(bgp_palette(&mut img, 0xF0, 2).is_err());
+        assert!(bgp_palette(&mut img, 0xE4, 1).is_err());
+    }
+}
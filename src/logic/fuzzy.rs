@@ -0,0 +1,117 @@
+use crate::img::{Color, Image};
+use crate::tile::Palettes;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Replaces colors by a close enough "representative" color, i.e. one whose distance to it is at
+/// most `threshold`. Representatives are the palette's colors if one is given, then the most
+/// frequent colors across all images.
+pub fn merge_colors(imgs: &mut [Image], threshold: u8, palette: Option<&Palettes>) {
+    // Most used colors first, the order they appear in breaking ties
+    let mut colors: Vec<_> = super::color_usage(imgs).into_iter().collect();
+    colors.sort_by_key(|&(_, (count, first))| (Reverse(count), first));
+
+    let mut representatives: Vec<Color> = palette.map_or_else(Vec::new, |pal| {
+        pal.iter()
+            .enumerate()
+            .flat_map(|(i, colors)| colors[..pal.palette_len(i)].iter().cloned())
+            .collect()
+    });
+    let nb_fixed = representatives.len(); // How many come from the palette
+    let mut mapping = HashMap::new();
+
+    for (color, _) in colors {
+        let is_close = |rep: &&Color| {
+            // Never merge colors with different transparency
+            rep.rgba()[3] == color.rgba()[3] && color.distance(rep) <= threshold
+        };
+
+        // Colors close to several of the palette's (but not exactly one of them) are ambiguous
+        if !representatives[..nb_fixed]
+            .iter()
+            .any(|rep| rep.matches(color))
+        {
+            let mut candidates: Vec<_> = representatives[..nb_fixed]
+                .iter()
+                .filter(is_close)
+                .map(Color::rgba)
+                .collect();
+            candidates.dedup();
+            if candidates.len() > 1 {
+                eprintln!(
+                    "Warning: color {:X} is close to {} of the palette's colors, using the closest",
+                    color,
+                    candidates.len()
+                );
+            }
+        }
+
+        let closest = representatives
+            .iter()
+            .filter(is_close)
+            .min_by_key(|rep| color.distance(rep))
+            .cloned();
+        match closest {
+            Some(rep) => {
+                if &rep != color {
+                    mapping.insert(color.clone(), rep);
+                }
+            }
+            None => representatives.push(color.clone()),
+        }
+    }
+
+    for pixel in imgs.iter_mut().flat_map(Image::pixels_mut) {
+        if let Some(rep) = mapping.get(pixel) {
+            *pixel = rep.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    fn image(colors: &[(u8, u8, u8)]) -> Image {
+        Image::new(
+            colors.len() as u32,
+            1,
+            colors
+                .iter()
+                .map(|&rgb| Color::new(Color::rgb_to_rgba(rgb), None))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn most_frequent_wins() {
+        let mut img = image(&[(100, 100, 100), (101, 100, 100), (100, 100, 100), (0, 0, 0)]);
+        merge_colors(slice::from_mut(&mut img), 4, None);
+        assert_eq!(img.pixels()[1], img.pixels()[0]);
+        assert_eq!(img.pixels()[3], Color::default());
+    }
+
+    #[test]
+    fn palette_colors_win() {
+        let mut palette = Palettes::new();
+        palette
+            .push(Color::new(Color::rgb_to_rgba((101, 100, 100)), None))
+            .unwrap();
+        let mut img = image(&[(100, 100, 100), (100, 100, 100), (0, 0, 0)]);
+        merge_colors(slice::from_mut(&mut img), 4, Some(&palette));
+        assert_eq!(img.pixels()[0], palette[0][0]);
+        assert_eq!(img.pixels()[2], Color::default());
+    }
+
+    #[test]
+    fn across_images() {
+        let mut imgs = [
+            image(&[(100, 100, 100)]),
+            image(&[(101, 100, 100), (101, 100, 100)]),
+        ];
+        merge_colors(&mut imgs, 4, None);
+        assert_eq!(imgs[0].pixels()[0], imgs[1].pixels()[0]);
+        assert_eq!(imgs[1].pixels()[0].rgba(), [101, 100, 100, 255]);
+    }
+}
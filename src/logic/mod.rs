@@ -1,51 +1,367 @@
-use crate::args::Slice;
-use crate::img::{self, Color, ImageReader, PngReader};
+use crate::args::{SharedColor, Slice, SpriteColor};
+use crate::img::{self, Color, ReadOptions, Rounding};
 use crate::tile::{Block, Palettes, Tile};
+use crate::util;
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::error;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
-use std::io;
-use std::ops::Deref;
+use std::io::{self, Cursor, Read};
+use std::iter;
 use std::path::{self, Path};
 
+mod dmg;
+mod fuzzy;
 mod palettes;
+pub use palettes::ColorOrder;
+use palettes::PackParams;
+mod permute;
+mod quantize;
+pub mod report;
+use report::PaletteUsage;
 mod tiles;
 pub use tiles::TileCollection;
 
 pub struct Params<'a, P: AsRef<Path> + ?Sized> {
+    #[allow(dead_code)] // Not used yet
     pub verbosity: u64,
 
-    pub path: &'a P,
+    pub paths: Vec<&'a P>, // All images share the same palettes; `-` is stdin
 
     pub block_height: u8,
     pub block_width: u8,
 
     pub slices: Option<Vec<Slice>>, // x, y (in pixels), w, h (in tiles)
     pub nb_blocks: usize,           // Hint to allocate the `Vec` up-front
+    pub read: ReadOptions,          // How to read the images
     pub palette: Option<Palettes>,
+    pub max_palettes: usize, // Counts the given palettes too if generating the rest
+    pub generate_rest: bool, // Generate palettes for blocks that fit none of `palette`'s
+    pub color_order: Option<ColorOrder>,
+    pub by_index: bool, // Put colors in the slot given by their PNG palette index
+    pub fuzziness: Option<u8>, // Threshold for merging colors
+    pub rounding: Rounding, // How colors are converted to RGB555
+    pub merge_rgb555: bool, // Merge colors that are identical in RGB555, instead of warning
+    pub report: bool,   // Compute how palettes are used
+    pub sprite: Option<SpriteColor>, // The color reserved for transparency, if making sprites
+    pub shared_color: Option<SharedColor>, // Color to put in slot 0 of all palettes
 
     pub dedup: bool,
     pub horiz_flip: bool,
     pub vert_flip: bool,
+    #[allow(dead_code)] // Not used yet
     pub base: u8,
     pub bgp: Option<u8>,
     pub bpp: u8,
 }
 
-pub fn process_file<P: AsRef<Path> + ?Sized>(
+/// Processes all images, which share a single set of palettes; returns the palettes, each
+/// image's palette map and tiles, and how the palettes are used if `params.report` is set.
+#[allow(clippy::type_complexity)]
+pub fn process_files<P: AsRef<Path> + ?Sized>(
     params: Params<P>,
-) -> Result<(Vec<[Color; 4]>, Vec<u16>, TileCollection), ProcessingError> {
+) -> Result<
+    (
+        Vec<[Color; 4]>,
+        Vec<(Vec<u16>, TileCollection)>,
+        Option<Vec<PaletteUsage>>,
+    ),
+    ProcessingError,
+> {
+    let mut imgs = Vec::with_capacity(params.paths.len());
+    let mut img_slices = Vec::with_capacity(params.paths.len()); // Slices embedded in each image
+    let mut transparent = None;
+
+    for (i, &path) in params.paths.iter().enumerate() {
+        // Stdin is read whole first, as it can't be rewound
+        let input: Box<dyn Read> = if util::is_stdio(path.as_ref().as_os_str()) {
+            let mut data = Vec::new();
+            io::stdin()
+                .read_to_end(&mut data)
+                .map_err(|err| ProcessingError::Io(path.as_ref().display(), err))?;
+            Box::new(Cursor::new(data))
+        } else {
+            Box::new(
+                File::open(path)
+                    .map_err(|err| ProcessingError::Io(path.as_ref().display(), err))?,
+            )
+        };
+        let img::Decoded {
+            image: mut img,
+            palette: img_palette,
+            slices,
+            collapsed,
+        } = img::read(input, &params.read).map_err(|err| match err {
+            img::ReadError::UnknownFormat => {
+                ProcessingError::UnknownFormat(path.as_ref().display())
+            }
+            err => ProcessingError::Reading(err),
+        })?;
+        for collapsed in collapsed {
+            let names: Vec<_> = collapsed
+                .sources
+                .iter()
+                .map(|rgba| {
+                    format!(
+                        "#{:04X}{:04X}{:04X}{:04X}",
+                        rgba[0], rgba[1], rgba[2], rgba[3]
+                    )
+                })
+                .collect();
+            eprintln!(
+                "Warning: {}: 16-bit colors {} all become ${:04X} in RGB555",
+                path.as_ref().display(),
+                names.join(", "),
+                collapsed.rgb555
+            );
+        }
+
+        // Sprites reserve color 0 for transparency ("index:n" refers to the first image's palette)
+        if i == 0 {
+            transparent = match &params.sprite {
+                None => None,
+                Some(SpriteColor::Transparent) => Some(Color::TRANSPARENT),
+                Some(SpriteColor::Color(color)) => Some(color.clone()),
+                Some(SpriteColor::Index(index)) => Some(
+                    img_palette
+                        .as_ref()
+                        .and_then(|palette| palette.get(usize::from(*index)))
+                        .cloned()
+                        .ok_or(ProcessingError::NoSpriteColor(*index))?,
+                ),
+            };
+        }
+        if let Some(transparent) = &transparent {
+            // Fully transparent pixels are always transparent, whichever color was picked
+            for pixel in img.pixels_mut() {
+                if pixel.rgba()[3] == 0 {
+                    *pixel = transparent.clone();
+                }
+            }
+        }
+        imgs.push(img);
+        img_slices.push(slices);
+    }
+
+    quantize::check_collisions(
+        &mut imgs,
+        params.rounding,
+        params.merge_rgb555,
+        params.palette.as_ref(),
+    );
+
+    if let Some(threshold) = params.fuzziness {
+        fuzzy::merge_colors(&mut imgs, threshold, params.palette.as_ref());
+    }
+    let mut dmg_palette = None; // DMG mode only supports a single image
+    for img in &mut imgs {
+        if let Some(bgp) = params.bgp {
+            dmg_palette = Some(dmg::bgp_palette(img, bgp, params.bpp)?);
+        }
+    }
+
+    // Extract tiles from all images, remembering how many blocks each has
+    let mut blocks = Vec::new();
+    let mut nb_blocks = Vec::with_capacity(imgs.len());
+    for (img, slices) in imgs.iter().zip(&img_slices) {
+        let img_blocks = extract_blocks(img, slices.as_deref(), &params)?;
+        nb_blocks.push(img_blocks.len());
+        blocks.extend(img_blocks);
+    }
+
+    // Either of these reserves slot 0 of all palettes
+    let reserved = match &params.shared_color {
+        None => transparent,
+        Some(SharedColor::Color(color)) => Some(color.clone()),
+        Some(SharedColor::MostFrequent) => most_frequent(&blocks),
+    };
+
+    // Generate the palette map, which maps one palette per block
+    // If a palette spec was given on the command line, ensure that tiles match it
+    // Otherwise, generate palettes from the colors used by tiles, checking that there are only 4 per tile
+
+    let mut pal_map = vec![0; blocks.len()]; // One entry per block, top to bottom, left to right
+
+    // Generates palettes for the given blocks, writing their IDs to `pal_map`
+    let usage = color_usage(&imgs);
+    let generate = |blocks: &[&Block], pal_map: &mut [u16], max_palettes| {
+        let mut palettes = palettes::pack_palettes(
+            blocks,
+            pal_map,
+            &PackParams {
+                bpp: params.bpp,
+                max_palettes,
+                by_index: params.by_index,
+                reserved: reserved.as_ref(),
+                order: params.color_order,
+                usage: &usage,
+            },
+        )?;
+
+        // Try reordering the palettes' colors to improve deduplication, leaving pinned ones alone
+        // (unless a specific order was requested)
+        if params.dedup && params.color_order.is_none() {
+            permute::permute_palettes(
+                blocks,
+                pal_map,
+                &mut palettes,
+                1 << params.bpp,
+                (params.horiz_flip, params.vert_flip),
+                |color| {
+                    reserved
+                        .as_ref()
+                        .is_some_and(|reserved| reserved.matches(color))
+                        || (params.by_index && color.palette_index().is_some())
+                },
+            );
+        }
+        Ok::<_, ProcessingError>(palettes)
+    };
+
+    let palettes = if let Some(palette) = dmg_palette {
+        // All blocks use the single palette
+        vec![palette]
+    } else if let Some(pal) = &params.palette {
+        // Check that the palette's size matches the bpp setting
+        for i in 0..pal.nb_palettes() {
+            if pal.palette_len(i) > 1 << params.bpp {
+                return Err(ProcessingError::BppMismatch(
+                    i,
+                    pal.palette_len(i),
+                    params.bpp,
+                ));
+            }
+        }
+
+        use_given_palettes(
+            &blocks,
+            pal,
+            &mut pal_map,
+            params.generate_rest,
+            params.max_palettes,
+            generate,
+        )?
+    } else {
+        let blocks: Vec<&Block> = blocks.iter().collect();
+        generate(&blocks, &mut pal_map, params.max_palettes)?
+    };
+
+    let report = if params.report {
+        let images = nb_blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(image, &nb)| iter::repeat_n(image, nb));
+        let blocks: Vec<_> = images.zip(&blocks).collect();
+        Some(report::palette_usage(
+            &blocks,
+            &pal_map,
+            &palettes,
+            params.palette.as_ref().map_or(0, Palettes::nb_palettes),
+            1 << params.bpp,
+        ))
+    } else {
+        None
+    };
+
+    // Generate each image's tile data, keeping them grouped by blocks
+
+    let mut outputs = Vec::with_capacity(nb_blocks.len());
+    let mut start = 0;
+    for nb in nb_blocks {
+        let range = start..start + nb;
+        let mut tile_data = TileCollection::new(params.dedup, params.horiz_flip, params.vert_flip);
+
+        for (block, pal_id) in blocks[range.clone()].iter().zip(&pal_map[range.clone()]) {
+            tile_data.add_block(block, &palettes[usize::from(*pal_id)]);
+        }
+        outputs.push((pal_map[range].to_vec(), tile_data));
+        start += nb;
+    }
+
+    Ok((palettes, outputs, report))
+}
+
+/// Maps each block to the first of the given palettes that fits it. Blocks that fit none of them
+/// are an error, unless `generate_rest` is set: palettes are then generated for them with
+/// `generate`, and appended after the given ones, which count towards `max_palettes`.
+fn use_given_palettes<'a, G>(
+    blocks: &'a [Block],
+    pal: &Palettes,
+    pal_map: &mut [u16],
+    generate_rest: bool,
+    max_palettes: usize,
+    generate: G,
+) -> Result<Vec<[Color; 4]>, ProcessingError<'static>>
+where
+    G: FnOnce(&[&'a Block], &mut [u16], usize) -> Result<Vec<[Color; 4]>, ProcessingError<'static>>,
+{
+    let mut unmatched = Vec::new(); // Blocks that fit none of the palettes
+    for (i, block) in blocks.iter().enumerate() {
+        // Find a suitable palette for the whole block
+        let mut is_candidate = vec![true; pal.nb_palettes()];
+
+        for tile in block.tiles() {
+            for pixel in tile.pixels() {
+                for i in 0..pal.nb_palettes() {
+                    // Don't perform a costly check if the palette has already been eliminated
+                    // TODO: if the color has already been seen, no need to look it up again
+                    // Only consider the palette's actual colors, not its padding
+                    if is_candidate[i]
+                        && !pal[i][..pal.palette_len(i)]
+                            .iter()
+                            .any(|color| color.matches(pixel))
+                    {
+                        is_candidate[i] = false;
+                    }
+                }
+            }
+        }
+
+        // Since the palette is already given on the CLI, we don't need to try to optimize: just pick one
+        if let Some((index, _)) = is_candidate.iter().enumerate().find(|(_, &yes)| yes) {
+            pal_map[i] = index.try_into().unwrap();
+        } else if generate_rest {
+            unmatched.push(i);
+        } else {
+            return Err(ProcessingError::NoPaletteFor(
+                block.x(),
+                block.y(),
+                block.width(),
+                block.height(),
+            ));
+        }
+    }
+
+    let mut palettes = pal.to_vec();
+    if !unmatched.is_empty() {
+        // The given palettes are locked in the first slots, generated ones go after them
+        let nb_locked = palettes.len();
+        let rest: Vec<&Block> = unmatched.iter().map(|&i| &blocks[i]).collect();
+        let mut rest_map = vec![0; rest.len()];
+        let max_palettes = max_palettes.saturating_sub(nb_locked);
+        palettes.extend(generate(&rest, &mut rest_map, max_palettes)?);
+
+        for (&i, id) in unmatched.iter().zip(rest_map) {
+            pal_map[i] = u16::try_from(nb_locked + usize::from(id)).unwrap();
+        }
+    }
+    Ok(palettes)
+}
+
+/// Splits the image into blocks, following the slices if any were given, either embedded in the
+/// image or on the command line.
+fn extract_blocks<'a, P: AsRef<Path> + ?Sized>(
+    img: &'a img::Image,
+    img_slices: Option<&[Slice]>,
+    params: &Params<P>,
+) -> Result<Vec<Block<'a>>, ProcessingError<'static>> {
     let (blk_width, blk_height) = (
         u32::from(params.block_width),
         u32::from(params.block_height),
     );
-    let file = File::open(params.path)
-        .map_err(|err| ProcessingError::Io(params.path.as_ref().display(), err))?;
-
-    // TODO: Support other file formats?
-    let img = PngReader::new(file)?.read_image()?;
 
     // If no slices were given, use the whole image
     let (width, height) = (img.width(), img.height());
@@ -55,24 +371,44 @@ pub fn process_file<P: AsRef<Path> + ?Sized>(
         width: width / 8,
         height: height / 8,
     }];
-    let (slices, nb_blocks) = match params.slices.as_ref() {
-        Some(slices) => (slices.as_slice().iter(), params.nb_blocks),
-        None => {
+    let (slices, nb_blocks) = match (img_slices, params.slices.as_ref()) {
+        (Some(slices), _) => {
+            // Unlike the command-line ones, these have not been checked against the block size
+            if let Some(slice) = slices
+                .iter()
+                .find(|slice| slice.width % blk_width != 0 || slice.height % blk_height != 0)
+            {
+                return Err(ProcessingError::SliceNotBlock(
+                    slice.clone(),
+                    params.block_width,
+                    params.block_height,
+                ));
+            }
+            (slices.iter(), slices.len())
+        }
+        (None, Some(slices)) => (slices.as_slice().iter(), params.nb_blocks),
+        (None, None) => {
             if width % 8 != 0 {
                 return Err(ProcessingError::WidthNotTiled(width));
             }
             if height % 8 != 0 {
                 return Err(ProcessingError::HeightNotTiled(height));
             }
-            if width % (blk_width) != 0 {
-                return Err(ProcessingError::WidthNotBlock(width, params.block_width));
+            if (width / 8) % blk_width != 0 {
+                return Err(ProcessingError::WidthNotBlock(
+                    width / 8,
+                    params.block_width,
+                ));
             }
-            if height % (blk_height) != 0 {
-                return Err(ProcessingError::HeightNotBlock(height, params.block_height));
+            if (height / 8) % blk_height != 0 {
+                return Err(ProcessingError::HeightNotBlock(
+                    height / 8,
+                    params.block_height,
+                ));
             }
             (
                 whole_image.iter(),
-                ((width / blk_width) * (height / blk_height)) as usize,
+                ((width / 8 / blk_width) * (height / 8 / blk_height)) as usize,
             )
         }
     };
@@ -107,7 +443,7 @@ pub fn process_file<P: AsRef<Path> + ?Sized>(
         // Generate tiles vertically first, as 8x16 mode requires contiguous vertical tile IDs
         for ofs_x in 0..slice.width {
             for ofs_y in 0..slice.height {
-                let tile = Tile::from_image(&img, slice.x + ofs_x * 8, slice.y + ofs_y * 8);
+                let tile = Tile::from_image(img, slice.x + ofs_x * 8, slice.y + ofs_y * 8);
                 let idx = usize::try_from(ofs_x).unwrap() * usize::try_from(height_blk).unwrap()
                     + usize::try_from(ofs_y).unwrap();
                 assert!(
@@ -121,65 +457,61 @@ pub fn process_file<P: AsRef<Path> + ?Sized>(
         }
     }
 
-    // Generate the palette map, which maps one palette per block
-    // If a palette spec was given on the command line, ensure that tiles match it
-    // Otherwise, generate palettes from the colors used by tiles, checking that there are only 4 per tile
+    Ok(blocks)
+}
 
-    let mut pal_map = vec![0; nb_blocks]; // One entry per block, top to bottom, left to right
+/// Counts how many pixels use each color, and the index of the first one (counting across images).
+fn color_usage(imgs: &[img::Image]) -> HashMap<&Color, (usize, usize)> {
+    let mut usage = HashMap::new();
+    let pixels = imgs.iter().flat_map(|img| img.pixels());
+    for (i, pixel) in pixels.enumerate() {
+        usage.entry(pixel).or_insert((0, i)).0 += 1;
+    }
+    usage
+}
 
-    let palettes = if let Some(pal) = params.palette {
-        // Check that the palette's size matches the bpp setting
-        for (i, palette) in pal.deref().iter().enumerate() {
-            if palette.len() > 1 << params.bpp {
-                return Err(ProcessingError::BppMismatch(i, palette.len(), params.bpp));
-            }
-        }
+/// Returns the color used by the most pixels within the blocks, favoring the first one on ties.
+fn most_frequent(blocks: &[Block]) -> Option<Color> {
+    let mut usage = HashMap::new();
+    let pixels = blocks
+        .iter()
+        .flat_map(|block| block.tiles())
+        .flat_map(|tile| tile.pixels());
+    for (i, pixel) in pixels.enumerate() {
+        usage.entry(pixel).or_insert((0, i)).0 += 1;
+    }
+    usage
+        .into_iter()
+        .max_by_key(|&(_, (count, first))| (count, Reverse(first)))
+        .map(|(color, _)| color.clone())
+}
 
-        for (i, block) in blocks.iter().enumerate() {
-            // Find a suitable palette for the whole block
-            let mut is_candidate = vec![true; pal.nb_palettes().into()];
-
-            for tile in block.tiles() {
-                for pixel in tile.pixels() {
-                    for i in 0..usize::from(pal.nb_palettes()) {
-                        // Don't perform a costly check if the palette has already been eliminated
-                        // TODO: if the color has already been seen, no need to look it up again
-                        if is_candidate[i] && !pal[i].contains(pixel) {
-                            is_candidate[i] = false;
-                        }
-                    }
-                }
-            }
+/// A block's location, and the colors it uses (for error reporting)
+#[derive(Debug)]
+pub struct BlockColors {
+    pub x: u32,
+    pub y: u32,
+    pub width: usize,  // In tiles
+    pub height: usize, // In tiles
+    pub colors: Vec<Color>,
+}
 
-            // Since the palette is already given on the CLI, we don't need to try to optimize: just pick one
-            if let Some((index, _)) = is_candidate.iter().enumerate().find(|(_, &yes)| yes) {
-                pal_map[i] = index.try_into().unwrap();
-            } else {
-                return Err(ProcessingError::NoPaletteFor(
-                    block.x(),
-                    block.y(),
-                    block.width(),
-                    block.height(),
-                ));
-            }
+impl Display for BlockColors {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        // Report the block's size in pixels
+        write!(
+            fmt,
+            "(x: {}, y: {}, width: {}, height: {}):",
+            self.x,
+            self.y,
+            self.width * 8,
+            self.height * 8
+        )?;
+        for color in &self.colors {
+            write!(fmt, " {:X}", color)?;
         }
-
-        pal.colors()
-    } else {
-        palettes::pack_palettes(&blocks, &mut pal_map, params.bpp)?
-    };
-
-    // Generate tile data, keeping them grouped by blocks
-
-    let mut tile_data = TileCollection::new(params.dedup, params.horiz_flip, params.vert_flip);
-
-    for (block, pal_id) in blocks.iter().zip(&pal_map) {
-        tile_data.add_block(block, &palettes[usize::from(*pal_id)]);
+        Ok(())
     }
-
-    // TODO: try rotating colors in the palettes to improve flipping optimization
-
-    Ok((palettes, pal_map, tile_data))
 }
 
 #[derive(Debug)]
@@ -191,10 +523,16 @@ pub enum ProcessingError<'a> {
     BppMismatch(usize, usize, u8),
     Io(path::Display<'a>, io::Error),
     NoPaletteFor(u32, u32, usize, usize),
+    NoRoomForReserved(BlockColors, Color, usize), // Block, reserved color, room left
+    NoSpriteColor(u8),
     OobSlice(Slice),
-    PngDecoding(png::DecodingError),
-    PngReading(img::PngReadError),
+    SliceNotBlock(Slice, u8, u8), // Slice, block width, block height
+    Reading(img::ReadError),
+    UnknownFormat(path::Display<'a>),
+    SlotConflict(BlockColors, usize),
     TooManyColors(u32, u32, usize, usize, u8),
+    TooManyLuminances(usize, u8, usize), // Found, BGP, shades available
+    TooManyPalettes(usize, usize, Vec<BlockColors>), // Needed, maximum, blocks needing the extra ones
 }
 
 impl Display for ProcessingError<'_> {
@@ -236,9 +574,31 @@ impl Display for ProcessingError<'_> {
                 w * 8,
                 h * 8
             ),
+            NoRoomForReserved(block, reserved, room) => write!(
+                fmt,
+                "Block {} leaves no room for {:X}, which is in every palette (only {} other colors fit)",
+                block, reserved, room
+            ),
+            NoSpriteColor(index) => {
+                write!(fmt, "Sprite color #{} is not in the image's palette", index)
+            }
             OobSlice(slice) => write!(fmt, "Slice {} is not within the image's bounds", slice),
-            PngDecoding(err) => err.fmt(fmt),
-            PngReading(err) => err.fmt(fmt),
+            SliceNotBlock(slice, width, height) => write!(
+                fmt,
+                "Slice {} cannot be divided into {}x{}-tile blocks",
+                slice, width, height
+            ),
+            Reading(err) => err.fmt(fmt),
+            UnknownFormat(name) => write!(
+                fmt,
+                "{}: Unknown image format (only PNG, BMP, GIF and Aseprite are supported)",
+                name
+            ),
+            SlotConflict(block, slot) => write!(
+                fmt,
+                "Block {} uses several colors whose indices map to palette slot {}",
+                block, slot
+            ),
             TooManyColors(x, y, w, h, bpp) => write!(
                 fmt,
                 "Block (x: {}, y: {}, width: {}, height: {}) contains more than {} colors",
@@ -248,6 +608,22 @@ impl Display for ProcessingError<'_> {
                 h * 8,
                 1 << bpp
             ),
+            TooManyLuminances(found, bgp, shades) => write!(
+                fmt,
+                "Image contains {} different luminances, but BGP ${:02X} only provides {} shades",
+                found, bgp, shades
+            ),
+            TooManyPalettes(needed, max, blocks) => {
+                write!(
+                    fmt,
+                    "{} palettes are needed, but only {} are allowed; these blocks need the extra palettes:",
+                    needed, max
+                )?;
+                for block in blocks {
+                    write!(fmt, "\n\t{}", block)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -261,23 +637,81 @@ impl error::Error for ProcessingError<'_> {
             HeightNotBlock(..) | WidthNotBlock(..) => None,
             BppMismatch(..) => None,
             Io(_, err) => Some(err),
-            NoPaletteFor(..) => None,
-            OobSlice(..) => None,
-            PngDecoding(err) => Some(err),
-            PngReading(err) => Some(err),
-            TooManyColors(..) => None,
+            NoPaletteFor(..) | NoRoomForReserved(..) | NoSpriteColor(..) => None,
+            OobSlice(..) | SliceNotBlock(..) => None,
+            SlotConflict(..) => None,
+            Reading(err) => Some(err),
+            UnknownFormat(..) => None,
+            TooManyColors(..) | TooManyLuminances(..) | TooManyPalettes(..) => None,
         }
     }
 }
 
-impl From<png::DecodingError> for ProcessingError<'_> {
-    fn from(err: png::DecodingError) -> Self {
-        Self::PngDecoding(err)
-    }
-}
-
-impl From<img::PngReadError> for ProcessingError<'_> {
-    fn from(err: img::PngReadError) -> Self {
-        Self::PngReading(err)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    #[test]
+    fn generate_rest() {
+        let rgb = |rgb| Color::new(Color::rgb_to_rgba(rgb), None);
+        let (black, white, red, blue) = (
+            rgb((0, 0, 0)),
+            rgb((255, 255, 255)),
+            rgb((255, 0, 0)),
+            rgb((0, 0, 255)),
+        );
+        // Black and white, red and blue, then all white
+        let pixels = (0..3 * 64)
+            .map(|i| match (i % 24 / 8, i % 8 < 4) {
+                (0, true) => black.clone(),
+                (1, true) => red.clone(),
+                (1, false) => blue.clone(),
+                _ => white.clone(),
+            })
+            .collect();
+        let img = img::Image::new(24, 8, pixels);
+        let blocks: Vec<_> = (0..3)
+            .map(|i| {
+                let mut block = Block::new(1, (i * 8, 0));
+                block.add_tile(Tile::from_image(&img, i * 8, 0));
+                block
+            })
+            .collect();
+        let mut pal = Palettes::new();
+        pal.push_palette(&[black.clone(), white.clone()]).unwrap();
+
+        let usage = color_usage(slice::from_ref(&img));
+        let generate = |blocks: &[&Block], pal_map: &mut [u16], max_palettes| {
+            palettes::pack_palettes(
+                blocks,
+                pal_map,
+                &PackParams {
+                    bpp: 2,
+                    max_palettes,
+                    by_index: false,
+                    reserved: None,
+                    order: None,
+                    usage: &usage,
+                },
+            )
+        };
+
+        let mut pal_map = [0; 3];
+        let palettes = use_given_palettes(&blocks, &pal, &mut pal_map, true, 2, generate).unwrap();
+        assert_eq!(pal_map, [0, 1, 0]);
+        assert_eq!(palettes.len(), 2);
+        assert_eq!(palettes[0][..2], [black, white]);
+        assert!(palettes[1].contains(&red) && palettes[1].contains(&blue));
+
+        // The given palette counts towards the limit
+        assert!(matches!(
+            use_given_palettes(&blocks, &pal, &mut pal_map, true, 1, generate),
+            Err(ProcessingError::TooManyPalettes(1, 0, _))
+        ));
+        assert!(matches!(
+            use_given_palettes(&blocks, &pal, &mut pal_map, false, 2, generate),
+            Err(ProcessingError::NoPaletteFor(8, 0, 1, 1))
+        ));
     }
 }
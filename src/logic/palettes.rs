@@ -1,23 +1,96 @@
-use super::ProcessingError;
+use super::{BlockColors, ProcessingError};
 use crate::img::Color;
 use crate::tile::Block;
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// How colors are ordered within generated palettes
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ColorOrder {
+    /// By increasing luminance
+    DarkFirst,
+    /// By decreasing luminance
+    LightFirst,
+    /// Most used colors first
+    Frequency,
+    /// In the order they appear in the image (top to bottom, left to right)
+    Appearance,
+    /// By increasing PNG palette index
+    Index,
+}
+
+impl FromStr for ColorOrder {
+    type Err = UnknownOrder;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        use ColorOrder::*;
+
+        match name {
+            "dark-first" => Ok(DarkFirst),
+            "light-first" => Ok(LightFirst),
+            "frequency" => Ok(Frequency),
+            "appearance" => Ok(Appearance),
+            "index" => Ok(Index),
+            _ => Err(UnknownOrder(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownOrder(String);
+
+impl Display for UnknownOrder {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "Unknown color order \"{}\" (expected dark-first, light-first, frequency, appearance, or index)",
+            self.0
+        )
+    }
+}
+
+impl error::Error for UnknownOrder {}
+
+/// How palettes are to be generated
+pub struct PackParams<'a> {
+    pub bpp: u8,
+    pub max_palettes: usize,
+    pub by_index: bool, // Put colors in the slot given by their palette index
+    pub reserved: Option<&'a Color>, // Color put in slot 0 of all palettes
+    pub order: Option<ColorOrder>, // If `None`, colors are ordered however is convenient
+    pub usage: &'a HashMap<&'a Color, (usize, usize)>, // See `super::color_usage`
+}
 
 pub fn pack_palettes<'a, 'b>(
-    blocks: &'a [Block],
-    pal_map: &'a mut Vec<u16>,
-    bpp: u8,
+    blocks: &[&'a Block],
+    pal_map: &'a mut [u16],
+    params: &PackParams<'a>,
 ) -> Result<Vec<[Color; 4]>, ProcessingError<'b>> {
-    let mut block_palettes = vec![Some(Vec::with_capacity(1 << bpp)); blocks.len()];
+    let PackParams {
+        bpp,
+        max_palettes,
+        by_index,
+        reserved,
+        order,
+        usage,
+    } = *params;
+    let size = 1 << bpp;
+    // The reserved color (if any) is put in slot 0 of every palette, leaving one less slot
+    let nb_free = size - reserved.map_or(0, |_| 1);
+    let mut block_palettes = vec![Vec::with_capacity(size); blocks.len()];
 
     // First, determine the colors used by each block
-    for (i, block) in blocks.iter().enumerate() {
-        let colors = block_palettes[i].as_mut().unwrap(); // We only have `Some`s at this point
+    for (i, &block) in blocks.iter().enumerate() {
+        let colors = &mut block_palettes[i];
 
         for tile in block.tiles() {
             for pixel in tile.pixels() {
                 if !colors.contains(&pixel) {
-                    if colors.len() == 1 << bpp {
+                    if colors.len() == size {
                         return Err(ProcessingError::TooManyColors(
                             block.x(),
                             block.y(),
@@ -31,87 +104,472 @@ pub fn pack_palettes<'a, 'b>(
                 }
             }
         }
+
+        if let Some(reserved) = reserved {
+            colors.retain(|color| !reserved.matches(color));
+            if colors.len() > nb_free {
+                return Err(ProcessingError::NoRoomForReserved(
+                    BlockColors {
+                        x: block.x(),
+                        y: block.y(),
+                        width: block.width(),
+                        height: block.height(),
+                        colors: colors.iter().map(|&color| color.clone()).collect(),
+                    },
+                    reserved.clone(),
+                    nb_free,
+                ));
+            }
+        }
+
+        // If respecting indices, two colors claiming the same slot can never share a palette
+        if by_index {
+            let mut slots = vec![None; size];
+            slots[0] = reserved;
+            for &color in colors.iter() {
+                if let Some(slot) = slot(color, size, by_index) {
+                    if let Some(other) = slots[slot].replace(color) {
+                        return Err(ProcessingError::SlotConflict(
+                            BlockColors {
+                                x: block.x(),
+                                y: block.y(),
+                                width: block.width(),
+                                height: block.height(),
+                                colors: vec![other.clone(), color.clone()],
+                            },
+                            slot,
+                        ));
+                    }
+                }
+            }
+        }
     }
 
     // From the "requests", generate the palettes
     // This amounts to solving the bin packing problem, yes, but we can usually simplify it a bit
-    let mut palettes = PaletteCollection::new();
-
-    // First step: any palettes that are fully-sized, will be kept as-is, obviously
-    for i in 0..blocks.len() {
-        if let Some(pal) = &block_palettes[i] {
-            if pal.len() == 1 << bpp {
-                // Replace the palette in the Vec with a None, and retrieve it
-                let mut palette = [None];
-                block_palettes[i..=i].swap_with_slice(&mut palette);
-
-                // Add the palette to the vector
-                pal_map[i] = palettes.insert(&palette[0].unwrap()).unwrap();
-            }
+
+    // First step: prune duplicates, and subsets of other requests, since they will fit wherever the
+    // larger request ends up. Processing the largest requests first makes this a single pass;
+    // it also means that any fully-sized palettes are kept as-is, obviously
+    let mut requests: Vec<&[&Color]> = block_palettes.iter().map(Vec::as_slice).collect();
+    requests.sort_by_key(|request| Reverse(request.len())); // Stable, so order is kept otherwise
+    let mut pruned: Vec<&[&Color]> = Vec::with_capacity(requests.len());
+    for request in requests {
+        if !pruned.iter().any(|other| is_subset(request, other)) {
+            pruned.push(request);
+        }
+    }
+
+    // Now, allocate the remaining requests
+    let mut palettes = PaletteCollection::new(size, by_index, reserved);
+    for request in pruned {
+        palettes.insert(request);
+    }
+    palettes.optimize();
+
+    // Map each block to the palette its request ended up in
+    let pal_ids: Vec<_> = block_palettes
+        .iter()
+        .map(|colors| {
+            palettes
+                .find(colors)
+                .expect("A block's colors were not allocated a palette")
+        })
+        .collect();
+
+    // Check that we are within budget, otherwise report the blocks at fault
+    let nb_palettes = palettes.palettes.len();
+    if nb_palettes > max_palettes {
+        // Blame the least used palettes, since those are the easiest to get rid of
+        let mut usage = vec![0usize; nb_palettes];
+        for &id in &pal_ids {
+            usage[id] += 1;
         }
+        let mut order: Vec<usize> = (0..nb_palettes).collect();
+        order.sort_by_key(|&id| Reverse(usage[id]));
+        let extra = &order[max_palettes..];
+
+        return Err(ProcessingError::TooManyPalettes(
+            nb_palettes,
+            max_palettes,
+            blocks
+                .iter()
+                .zip(&pal_ids)
+                .zip(&block_palettes)
+                .filter(|((_, id), _)| extra.contains(id))
+                .map(|((block, _), colors)| BlockColors {
+                    x: block.x(),
+                    y: block.y(),
+                    width: block.width(),
+                    height: block.height(),
+                    colors: colors.iter().map(|&color| color.clone()).collect(),
+                })
+                .collect(),
+        ));
+    }
+
+    // Finally, the palette IDs are known to fit
+    for (entry, id) in pal_map.iter_mut().zip(pal_ids) {
+        *entry = id.try_into().unwrap();
     }
 
-    // Now, prune subsets of already-allocated palettes
-    for i in 0..block_palettes.len() {
-        if let Some(pal) = &block_palettes[i] {
-            //
+    let cmp = |lhs: &Color, rhs: &Color| match order {
+        None => Ordering::Equal,
+        Some(ColorOrder::DarkFirst) => lhs
+            .luma_chroma()
+            .0
+            .partial_cmp(&rhs.luma_chroma().0)
+            .unwrap(),
+        Some(ColorOrder::LightFirst) => rhs
+            .luma_chroma()
+            .0
+            .partial_cmp(&lhs.luma_chroma().0)
+            .unwrap(),
+        Some(ColorOrder::Frequency) => {
+            let (lhs, rhs) = (usage[lhs], usage[rhs]);
+            (Reverse(lhs.0), lhs.1).cmp(&(Reverse(rhs.0), rhs.1))
         }
+        Some(ColorOrder::Appearance) => usage[lhs].1.cmp(&usage[rhs].1),
+        // Colors without an index go last
+        Some(ColorOrder::Index) => (lhs.palette_index().is_none(), lhs.palette_index())
+            .cmp(&(rhs.palette_index().is_none(), rhs.palette_index())),
+    };
+
+    // Remember to add magenta as padding!
+    Ok(palettes.gen_palettes(&Color::FILLER, cmp))
+}
+
+/// The slot a color must be put in, if any.
+fn slot(color: &Color, size: usize, by_index: bool) -> Option<usize> {
+    color
+        .palette_index()
+        .filter(|_| by_index)
+        .map(|index| usize::from(index) % size)
+}
+
+/// Checks whether all of `subset`'s colors are also in `set`.
+fn is_subset(subset: &[&Color], set: &[&Color]) -> bool {
+    subset.iter().all(|color| set.contains(color))
+}
+
+/// A palette being built, and the requests that have been allocated to it
+#[derive(Debug, Clone)]
+struct Palette<'a> {
+    colors: Vec<&'a Color>,
+    requests: Vec<&'a [&'a Color]>,
+}
+
+impl<'a> Palette<'a> {
+    /// How many colors would need to be added to this palette for it to hold the request
+    fn nb_new_colors(&self, request: &[&Color]) -> usize {
+        request
+            .iter()
+            .filter(|color| !self.colors.contains(color))
+            .count()
     }
 
-    // Now, if any palettes remain, allocate them
-    if block_palettes.iter().any(|pal| pal.is_some()) {
-        todo!();
+    /// Checks if the request can be added to this palette without overflowing it, or (if
+    /// respecting indices) two colors ending up claiming the same slot
+    fn fits(&self, request: &[&Color], size: usize, by_index: bool, reserved: bool) -> bool {
+        let new_colors = request.iter().filter(|color| !self.colors.contains(color));
+        let mut slots = vec![false; size];
+        slots[0] = reserved;
+
+        self.colors.len() + new_colors.clone().count() + usize::from(reserved) <= size
+            && self
+                .colors
+                .iter()
+                .chain(new_colors)
+                .filter_map(|color| slot(color, size, by_index))
+                .all(|slot| !std::mem::replace(&mut slots[slot], true))
     }
 
-    // TODO: remember to add magenta as padding!
+    fn add(&mut self, request: &'a [&'a Color]) {
+        for color in request {
+            if !self.colors.contains(color) {
+                self.colors.push(color);
+            }
+        }
+        self.requests.push(request);
+    }
+}
 
-    Ok(palettes.gen_palettes(&Color::new((0xFF, 0x00, 0xFF, 0xFF), None)))
+struct PaletteCollection<'a> {
+    size: usize,                 // How many colors each palette can hold
+    by_index: bool, // Whether colors must be put in the slot given by their palette index
+    reserved: Option<&'a Color>, // Color put in slot 0 of all palettes
+    palettes: Vec<Palette<'a>>,
 }
 
-struct PaletteCollection(Vec<[Option<Color>; 4]>);
+impl<'a> PaletteCollection<'a> {
+    pub fn new(size: usize, by_index: bool, reserved: Option<&'a Color>) -> Self {
+        Self {
+            size,
+            by_index,
+            reserved,
+            // There are typically 8 palettes at most
+            palettes: Vec::with_capacity(8),
+        }
+    }
 
-impl PaletteCollection {
-    pub fn new() -> Self {
-        // There are typically 8 palettes at most
-        Self(Vec::with_capacity(8))
+    /// Generates the final palettes; colors not pinned to a slot are sorted using `order`.
+    pub fn gen_palettes(
+        self,
+        filler: &Color,
+        order: impl Fn(&Color, &Color) -> Ordering,
+    ) -> Vec<[Color; 4]> {
+        self.palettes
+            .iter()
+            .map(|palette| {
+                let mut colors = [
+                    filler.clone(),
+                    filler.clone(),
+                    filler.clone(),
+                    filler.clone(),
+                ];
+                let mut used = [false; 4];
+                if let Some(reserved) = self.reserved {
+                    colors[0] = reserved.clone();
+                    used[0] = true;
+                }
+                // Place the colors that require a given slot first, then fill in the gaps
+                let (placed, mut free): (Vec<_>, Vec<_>) = palette
+                    .colors
+                    .iter()
+                    .partition(|color| slot(color, self.size, self.by_index).is_some());
+                for color in placed {
+                    let i = slot(color, self.size, self.by_index).unwrap();
+                    colors[i] = (*color).clone();
+                    used[i] = true;
+                }
+                free.sort_by(|lhs, rhs| order(lhs, rhs));
+                let gaps = (0..4).filter(|&i| !used[i]);
+                for (i, color) in gaps.zip(free) {
+                    colors[i] = (*color).clone();
+                }
+                colors
+            })
+            .collect()
     }
 
-    pub fn gen_palettes(self, filler: &Color) -> Vec<[Color; 4]> {
-        unimplemented!()
+    /// Picks the palette that the request would fit best in, i.e. the one that it shares the most
+    /// colors with, preferring fuller palettes to leave room in the others.
+    fn best_fit(&self, palettes: &[Palette], request: &[&Color]) -> Option<usize> {
+        palettes
+            .iter()
+            .enumerate()
+            .filter(|(_, palette)| {
+                palette.fits(request, self.size, self.by_index, self.reserved.is_some())
+            })
+            .map(|(i, palette)| (i, palette.nb_new_colors(request), palette.colors.len()))
+            .min_by_key(|&(_, nb_new, len)| (nb_new, Reverse(len)))
+            .map(|(i, ..)| i)
     }
 
-    pub fn insert(&mut self, palette: &[Color]) -> Option<u16> {
-        self.find(palette).or_else(|| {
-            // Ensure that we don't hit more than 65536 palettes
-            if self.0.len() == 65536 {
-                return None;
+    pub fn insert(&mut self, request: &'a [&'a Color]) {
+        // Try to find a palette the request could share colors with
+        // TODO: this may be suboptimal, but the problem is NP-complete...
+        match self.best_fit(&self.palettes, request) {
+            Some(i) => self.palettes[i].add(request),
+            // None found? Alright then, add a new palette
+            None => {
+                let mut palette = Palette {
+                    colors: Vec::with_capacity(self.size),
+                    requests: Vec::new(),
+                };
+                palette.add(request);
+                self.palettes.push(palette);
             }
+        }
+    }
+
+    /// Attempts to reduce the amount of palettes, by trying to redistribute all of a palette's
+    /// requests among the other palettes, and removing it if that succeeds.
+    /// This is repeated until no palette can be removed anymore.
+    pub fn optimize(&mut self) {
+        loop {
+            // Palettes with the fewest requests are the most likely to be redistributed
+            let mut order: Vec<usize> = (0..self.palettes.len()).collect();
+            order.sort_by_key(|&i| self.palettes[i].requests.len());
+
+            let removed = order.into_iter().find_map(|i| {
+                let mut others: Vec<_> = self
+                    .palettes
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .map(|(_, palette)| palette.clone())
+                    .collect();
+                let mut requests = self.palettes[i].requests.clone();
+                requests.sort_by_key(|request| Reverse(request.len()));
 
-            // If the palette wasn't found, try to find one it could share colors with
-            // TODO: this may be suboptimal, but the problem is NP-complete...
-            // TODO
+                requests
+                    .into_iter()
+                    .all(|request| {
+                        self.best_fit(&others, request)
+                            .map(|j| others[j].add(request))
+                            .is_some()
+                    })
+                    .then_some(others)
+            });
 
-            // None found? Alright then, add a new palette slot
-            self.0.resize_with(self.0.len() + 1, Default::default);
-            let new_pal = self.0.last().unwrap();
-            for i in 0..palette.len() {
-                todo!();
+            match removed {
+                Some(palettes) => self.palettes = palettes,
+                None => break,
             }
-            Some(self.0.len().try_into().unwrap())
-        })
+        }
     }
 
-    pub fn find(&self, palette: &[Color]) -> Option<u16> {
-        self.0.iter().enumerate().find_map(|(i, pal)| {
-            // See if the target contains all of our colors
-            palette
-                .iter()
-                .all(|color| {
-                    pal.iter()
-                        .any(|item| item.as_ref().map_or(false, |c| c == color))
-                })
-                // If so, return the ID
-                .then(|| i.try_into().unwrap())
-        })
+    pub fn find(&self, request: &[&Color]) -> Option<usize> {
+        self.palettes
+            .iter()
+            // See if the target contains all of our colors; if so, return the ID
+            .position(|pal| is_subset(request, &pal.colors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(shade: u8) -> Color {
+        Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), None)
+    }
+
+    #[test]
+    fn subset_shares_palette() {
+        let (a, b, c, d) = (gray(0), gray(1), gray(2), gray(3));
+        let full = [&a, &b, &c, &d];
+        let part = [&d, &b];
+
+        let mut palettes = PaletteCollection::new(4, false, None);
+        palettes.insert(&full);
+        palettes.insert(&part);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 1);
+        assert_eq!(palettes.find(&part), Some(0));
+    }
+
+    #[test]
+    fn disjoint_requests_merge() {
+        let (a, b, c, d) = (gray(0), gray(1), gray(2), gray(3));
+        let (lhs, rhs) = ([&a, &b], [&c, &d]);
+
+        let mut palettes = PaletteCollection::new(4, false, None);
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 1);
+    }
+
+    #[test]
+    fn overflow_needs_new_palette() {
+        let (a, b, c, d, e) = (gray(0), gray(1), gray(2), gray(3), gray(4));
+        let (lhs, rhs) = ([&a, &b, &c], [&c, &d, &e]);
+
+        let mut palettes = PaletteCollection::new(4, false, None);
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 2);
+        assert_eq!(palettes.find(&lhs), Some(0));
+        assert_eq!(palettes.find(&rhs), Some(1));
+    }
+
+    #[test]
+    fn optimize_removes_palettes() {
+        let colors: Vec<_> = (0..6).map(gray).collect();
+        let c = |i: usize| &colors[i];
+        // Greedily, the first two requests fill up a palette, forcing the last two apart;
+        // but the first two can then be moved into the others' palettes
+        let requests = [
+            vec![c(0), c(1)],
+            vec![c(2), c(3)],
+            vec![c(0), c(2), c(4)],
+            vec![c(1), c(3), c(5)],
+        ];
+
+        let mut palettes = PaletteCollection::new(4, false, None);
+        for request in &requests {
+            palettes.insert(request);
+        }
+        assert_eq!(palettes.palettes.len(), 3);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 2);
+        for request in &requests {
+            assert!(palettes.find(request).is_some());
+        }
+    }
+
+    #[test]
+    fn indices_pick_slots() {
+        let indexed =
+            |shade, index| Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), Some(index));
+        let (a, b, c, d) = (indexed(0, 3), indexed(1, 5), indexed(2, 2), gray(3));
+        // `b` claims slot 1 (5 % 4), so `d` is the only one left to fill slot 0
+        let (lhs, rhs) = ([&a, &b], [&c, &d]);
+
+        let mut palettes = PaletteCollection::new(4, true, None);
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        assert_eq!(palettes.palettes.len(), 1);
+        let palette = &palettes.gen_palettes(&Color::FILLER, |_, _| Ordering::Equal)[0];
+        assert_eq!(palette[3], a);
+        assert_eq!(palette[1], b);
+        assert_eq!(palette[2], c);
+        assert_eq!(palette[0], d);
+    }
+
+    #[test]
+    fn indices_conflict() {
+        let indexed =
+            |shade, index| Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), Some(index));
+        let (a, b) = (indexed(0, 1), indexed(1, 5));
+        let (lhs, rhs) = ([&a], [&b]);
+
+        // Both colors claim slot 1, so they cannot share a palette despite the room
+        let mut palettes = PaletteCollection::new(4, true, None);
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 2);
+    }
+
+    #[test]
+    fn reserved_takes_slot_0() {
+        let (a, b, c, d) = (gray(0), gray(1), gray(2), gray(3));
+        let (lhs, rhs) = ([&b, &c], [&c, &d]);
+
+        let mut palettes = PaletteCollection::new(4, false, Some(&a));
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        assert_eq!(palettes.palettes.len(), 1);
+        assert_eq!(
+            palettes.gen_palettes(&Color::FILLER, |_, _| Ordering::Equal)[0],
+            [a.clone(), b.clone(), c.clone(), d.clone()]
+        );
+
+        // Only 3 slots remain, so two pairs of colors no longer fit together
+        let e = gray(4);
+        let (lhs, rhs) = ([&a, &b], [&c, &d]);
+        let mut palettes = PaletteCollection::new(4, false, Some(&e));
+        palettes.insert(&lhs);
+        palettes.insert(&rhs);
+        palettes.optimize();
+        assert_eq!(palettes.palettes.len(), 2);
+    }
+
+    #[test]
+    fn order_skips_pinned_colors() {
+        let (a, b, c, d) = (gray(0), gray(1), gray(2), gray(3));
+        let request = [&c, &a, &b];
+
+        let mut palettes = PaletteCollection::new(4, false, Some(&d));
+        palettes.insert(&request);
+        let by_shade = |lhs: &Color, rhs: &Color| lhs.rgba().cmp(&rhs.rgba());
+        assert_eq!(
+            palettes.gen_palettes(&Color::FILLER, by_shade)[0],
+            [d.clone(), a.clone(), b.clone(), c.clone()]
+        );
     }
 }
@@ -0,0 +1,193 @@
+use super::tiles::IndexedTile;
+use crate::img::Color;
+use crate::tile::Block;
+use std::collections::HashSet;
+
+/// Reorders the colors within each palette, so that as many blocks as possible end up identical
+/// (or flips of each other) once indexed, and can thus be deduplicated.
+/// Colors for which `is_pinned` returns true are left in their slot.
+pub fn permute_palettes(
+    blocks: &[&Block],
+    pal_map: &[u16],
+    palettes: &mut [[Color; 4]],
+    size: usize,
+    (horiz_flip, vert_flip): (bool, bool),
+    is_pinned: impl Fn(&Color) -> bool,
+) {
+    let form = |block, palette: &[Color]| canonical(block, palette, horiz_flip, vert_flip);
+    // Each block's canonical form with the current palettes
+    let mut forms: Vec<_> = blocks
+        .iter()
+        .zip(pal_map)
+        .map(|(block, &id)| form(block, &palettes[usize::from(id)]))
+        .collect();
+
+    // Try all orders of each palette in turn, keeping the best one; this is greedy, but exhaustive
+    // search would be way too slow with more than a couple of palettes
+    for (id, palette) in palettes.iter_mut().enumerate() {
+        // Only the blocks using this palette change, so the others' forms are only computed once
+        let (mine, others): (Vec<_>, Vec<_>) =
+            (0..blocks.len()).partition(|&i| usize::from(pal_map[i]) == id);
+        let others: HashSet<_> = others.iter().map(|&i| &forms[i]).collect();
+        let nb_unique = |colors: &[Color]| {
+            let new: HashSet<_> = mine
+                .iter()
+                .map(|&i| form(blocks[i], colors))
+                .filter(|form| !others.contains(form))
+                .collect();
+            others.len() + new.len()
+        };
+
+        let original = palette.clone();
+        let free: Vec<usize> = (0..size)
+            .filter(|&slot| !is_pinned(&original[slot]))
+            .collect();
+        let mut best = (nb_unique(&original), original.clone());
+
+        for order in permutations(&free) {
+            let mut candidate = original.clone();
+            for (&slot, &src) in free.iter().zip(&order) {
+                candidate[slot] = original[src].clone();
+            }
+            let nb = nb_unique(&candidate);
+            if nb < best.0 {
+                best = (nb, candidate);
+            }
+        }
+        *palette = best.1;
+        for i in mine {
+            forms[i] = form(blocks[i], palette);
+        }
+    }
+}
+
+/// Computes a representation of the block that's identical for all blocks it can be deduplicated
+/// with, i.e. the smallest of its allowed flips.
+fn canonical(
+    block: &Block,
+    palette: &[Color],
+    horiz_flip: bool,
+    vert_flip: bool,
+) -> Vec<IndexedTile> {
+    let tiles: Vec<_> = block
+        .tiles()
+        .iter()
+        .map(|tile| IndexedTile::new(tile, palette))
+        .collect();
+    let (width, height) = (block.width(), block.height());
+    let flipped = |hflip: bool, vflip: bool| {
+        let mut flipped = Vec::with_capacity(tiles.len());
+        for y in 0..height {
+            for x in 0..width {
+                let x = if hflip { width - 1 - x } else { x };
+                let y = if vflip { height - 1 - y } else { y };
+                let tile = &tiles[y * width + x];
+                flipped.push(match (hflip, vflip) {
+                    (false, false) => tile.clone(),
+                    (true, false) => tile.hflipped(),
+                    (false, true) => tile.vflipped(),
+                    (true, true) => tile.hflipped().vflipped(),
+                });
+            }
+        }
+        flipped
+    };
+
+    let mut candidates = vec![tiles.clone()];
+    if horiz_flip {
+        candidates.push(flipped(true, false));
+    }
+    if vert_flip {
+        candidates.push(flipped(false, true));
+    }
+    if horiz_flip && vert_flip {
+        candidates.push(flipped(true, true));
+    }
+    candidates.into_iter().min().unwrap()
+}
+
+/// Generates all orderings of the given items.
+fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+    if items.is_empty() {
+        return vec![vec![]];
+    }
+    (0..items.len())
+        .flat_map(|i| {
+            let mut rest = items.to_vec();
+            let first = rest.remove(i);
+            permutations(&rest).into_iter().map(move |mut order| {
+                order.insert(0, first);
+                order
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::Image;
+    use crate::tile::Tile;
+
+    #[test]
+    fn all_permutations() {
+        let mut orders = permutations(&[0, 1, 2, 3]);
+        assert_eq!(orders.len(), 24);
+        orders.sort();
+        orders.dedup();
+        assert_eq!(orders.len(), 24);
+        assert_eq!(permutations(&[]), vec![Vec::<usize>::new()]);
+    }
+
+    #[test]
+    fn swapped_colors_are_merged() {
+        let gray = |shade| Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), None);
+        // Two tiles with the same shape, but with their colors in opposite order
+        let pixels = (0..2 * 64)
+            .map(|i| {
+                let (x, tile) = (i % 16 % 8, i % 16 / 8);
+                gray(if (x < 4) == (tile == 0) { 0 } else { 255 })
+            })
+            .collect();
+        let img = Image::new(16, 8, pixels);
+        let blocks: Vec<_> = (0..2)
+            .map(|i| {
+                let mut block = Block::new(1, (i * 8, 0));
+                block.add_tile(Tile::from_image(&img, i * 8, 0));
+                block
+            })
+            .collect();
+        let blocks: Vec<_> = blocks.iter().collect();
+        let pal_map = [0, 1];
+        let mut palettes = [
+            [gray(0), gray(255), Color::FILLER, Color::FILLER],
+            [gray(0), gray(255), Color::FILLER, Color::FILLER],
+        ];
+
+        permute_palettes(&blocks, &pal_map, &mut palettes, 4, (false, false), |_| {
+            false
+        });
+        assert_eq!(
+            canonical(blocks[0], &palettes[0], false, false),
+            canonical(blocks[1], &palettes[1], false, false)
+        );
+
+        // Pinning the colors prevents that
+        let mut palettes = [
+            [gray(0), gray(255), Color::FILLER, Color::FILLER],
+            [gray(0), gray(255), Color::FILLER, Color::FILLER],
+        ];
+        permute_palettes(
+            &blocks,
+            &pal_map,
+            &mut palettes,
+            4,
+            (false, false),
+            |color| color != &Color::FILLER,
+        );
+        assert_ne!(
+            canonical(blocks[0], &palettes[0], false, false),
+            canonical(blocks[1], &palettes[1], false, false)
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use crate::img::{Color, Image, Rounding};
+use crate::tile::Palettes;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Looks for distinct colors that become identical once converted to RGB555, and either warns
+/// about them, or replaces each group with a single color: the palette's if it contains one of
+/// them, otherwise the most used one.
+pub fn check_collisions(
+    imgs: &mut [Image],
+    rounding: Rounding,
+    merge: bool,
+    palette: Option<&Palettes>,
+) {
+    // Most used colors first, the order they appear in breaking ties
+    let mut colors: Vec<_> = super::color_usage(imgs).into_iter().collect();
+    colors.sort_by_key(|&(_, (count, first))| (Reverse(count), first));
+
+    // Transparent pixels are never written as colors, so they can't collide
+    let mut groups: HashMap<u16, Vec<&Color>> = HashMap::new();
+    for (color, _) in colors.into_iter().filter(|(color, _)| color.rgba()[3] != 0) {
+        let group = groups.entry(color.to_rgb555(rounding)).or_default();
+        // Colors that only differ by their palette index are not distinct
+        if !group.iter().any(|other| other.rgba() == color.rgba()) {
+            group.push(color);
+        }
+    }
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .collect();
+    groups.sort_unstable_by_key(|&(rgb555, _)| rgb555);
+
+    let mut mapping = HashMap::new();
+    for (rgb555, group) in groups {
+        if !merge {
+            let names: Vec<_> = group.iter().map(|color| format!("{:X}", color)).collect();
+            eprintln!(
+                "Warning: colors {} all become ${:04X} in RGB555",
+                names.join(", "),
+                rgb555
+            );
+            continue;
+        }
+
+        let in_palette = |color: &&&Color| {
+            palette.is_some_and(|pal| {
+                pal.iter().enumerate().any(|(i, colors)| {
+                    colors[..pal.palette_len(i)]
+                        .iter()
+                        .any(|pal_color| pal_color.matches(color))
+                })
+            })
+        };
+        let rep = group.iter().find(in_palette).unwrap_or(&group[0]);
+        for color in &group {
+            if color.rgba() != rep.rgba() {
+                mapping.insert(color.rgba(), (*rep).clone());
+            }
+        }
+    }
+
+    if !mapping.is_empty() {
+        for pixel in imgs.iter_mut().flat_map(|img| img.pixels_mut()) {
+            if let Some(rep) = mapping.get(&pixel.rgba()) {
+                *pixel = rep.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(colors: &[(u8, u8, u8)]) -> Image {
+        Image::new(
+            colors.len() as u32,
+            1,
+            colors
+                .iter()
+                .map(|&rgb| Color::new(Color::rgb_to_rgba(rgb), None))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn warning_changes_nothing() {
+        let mut imgs = [image(&[(8, 8, 8), (9, 9, 9)])];
+        check_collisions(&mut imgs, Rounding::Truncate, false, None);
+        assert_ne!(imgs[0].pixels()[0], imgs[0].pixels()[1]);
+    }
+
+    #[test]
+    fn most_used_wins() {
+        let mut imgs = [
+            image(&[(8, 8, 8), (9, 9, 9), (100, 100, 100)]),
+            image(&[(9, 9, 9)]),
+        ];
+        check_collisions(&mut imgs, Rounding::Truncate, true, None);
+        assert_eq!(imgs[0].pixels()[0], imgs[1].pixels()[0]);
+        assert_eq!(imgs[0].pixels()[1], imgs[1].pixels()[0]);
+        assert_ne!(imgs[0].pixels()[2], imgs[1].pixels()[0]);
+    }
+
+    #[test]
+    fn palette_color_wins() {
+        let mut palette = Palettes::new();
+        palette
+            .push(Color::new(Color::rgb_to_rgba((8, 8, 8)), None))
+            .unwrap();
+        let mut imgs = [image(&[(9, 9, 9), (9, 9, 9), (8, 8, 8)])];
+        check_collisions(&mut imgs, Rounding::Truncate, true, Some(&palette));
+        assert_eq!(imgs[0].pixels()[0], palette[0][0]);
+    }
+}
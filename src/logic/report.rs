@@ -0,0 +1,295 @@
+use crate::img::Color;
+use crate::tile::Block;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How one of the final palettes is used
+#[derive(Debug)]
+pub struct PaletteUsage {
+    pub locked: bool, // Given with `-P`, instead of generated
+    pub colors: Vec<ColorUsage>,
+    pub blocks: Vec<BlockRef>,
+}
+
+#[derive(Debug)]
+pub struct ColorUsage {
+    pub color: Color,
+    pub blocks: usize, // How many blocks use this color at least once
+    pub pixels: usize,
+}
+
+/// A block's location, in pixels
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockRef {
+    pub image: usize, // Index into the list of images
+    pub x: u32,
+    pub y: u32,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Computes how each palette's colors are used by the blocks mapped to it.
+/// `blocks` is each image's blocks, and `pal_map` their palette IDs, in the same order; the first
+/// `nb_locked` palettes are the ones given with `-P`.
+pub fn palette_usage(
+    blocks: &[(usize, &Block)],
+    pal_map: &[u16],
+    palettes: &[[Color; 4]],
+    nb_locked: usize,
+    size: usize,
+) -> Vec<PaletteUsage> {
+    let mut usage: Vec<_> = palettes
+        .iter()
+        .enumerate()
+        .map(|(id, palette)| PaletteUsage {
+            locked: id < nb_locked,
+            colors: palette[..size]
+                .iter()
+                .map(|color| ColorUsage {
+                    color: color.clone(),
+                    blocks: 0,
+                    pixels: 0,
+                })
+                .collect(),
+            blocks: Vec::new(),
+        })
+        .collect();
+
+    for (&(image, block), &id) in blocks.iter().zip(pal_map) {
+        let usage = &mut usage[usize::from(id)];
+        let mut used = vec![false; size];
+        for tile in block.tiles() {
+            for pixel in tile.pixels() {
+                // Same lookup as when indexing the tiles
+                let slot = usage
+                    .colors
+                    .iter()
+                    .position(|slot| slot.color.matches(pixel))
+                    .expect("Block mapped to a palette that doesn't fit it");
+                usage.colors[slot].pixels += 1;
+                used[slot] = true;
+            }
+        }
+        for (color, _) in usage.colors.iter_mut().zip(used).filter(|(_, used)| *used) {
+            color.blocks += 1;
+        }
+        usage.blocks.push(BlockRef {
+            image,
+            x: block.x(),
+            y: block.y(),
+            width: block.width() * 8,
+            height: block.height() * 8,
+        });
+    }
+
+    usage
+}
+
+/// The formats the report can be written in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = UnknownReportFormat;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(UnknownReportFormat(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownReportFormat(String);
+
+impl Display for UnknownReportFormat {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Unknown report format \"{}\"", self.0)
+    }
+}
+
+impl error::Error for UnknownReportFormat {}
+
+/// Writes the report; `images` are the names of the images, which blocks refer to.
+pub fn write<W: Write>(
+    output: &mut W,
+    usage: &[PaletteUsage],
+    images: &[String],
+    format: ReportFormat,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Text => {
+            for (id, palette) in usage.iter().enumerate() {
+                let locked = if palette.locked { " (locked)" } else { "" };
+                writeln!(
+                    output,
+                    "Palette {}{}: {} blocks",
+                    id,
+                    locked,
+                    palette.blocks.len()
+                )?;
+                for (slot, usage) in palette.colors.iter().enumerate() {
+                    writeln!(
+                        output,
+                        "\t#{} {:X}: {} blocks, {} pixels",
+                        slot, usage.color, usage.blocks, usage.pixels
+                    )?;
+                }
+                for block in &palette.blocks {
+                    write!(output, "\t")?;
+                    if images.len() > 1 {
+                        write!(output, "{} ", images[block.image])?;
+                    }
+                    writeln!(
+                        output,
+                        "(x: {}, y: {}, width: {}, height: {})",
+                        block.x, block.y, block.width, block.height
+                    )?;
+                }
+            }
+        }
+        ReportFormat::Json => {
+            writeln!(output, "{{\"palettes\": [")?;
+            for (id, palette) in usage.iter().enumerate() {
+                let colors: Vec<_> = palette
+                    .colors
+                    .iter()
+                    .map(|usage| {
+                        format!(
+                            "{{\"color\": \"{:X}\", \"blocks\": {}, \"pixels\": {}}}",
+                            usage.color, usage.blocks, usage.pixels
+                        )
+                    })
+                    .collect();
+                let blocks: Vec<_> = palette
+                    .blocks
+                    .iter()
+                    .map(|block| {
+                        format!(
+                            "{{\"image\": {}, \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}}}",
+                            json_string(&images[block.image]),
+                            block.x,
+                            block.y,
+                            block.width,
+                            block.height
+                        )
+                    })
+                    .collect();
+                writeln!(
+                    output,
+                    "\t{{\"id\": {}, \"locked\": {}, \"colors\": [{}], \"blocks\": [{}]}}{}",
+                    id,
+                    palette.locked,
+                    colors.join(", "),
+                    blocks.join(", "),
+                    if id + 1 == usage.len() { "" } else { "," }
+                )?;
+            }
+            writeln!(output, "]}}")?;
+        }
+    }
+    Ok(())
+}
+
+fn json_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len() + 2);
+    escaped.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::Image;
+    use crate::tile::Tile;
+
+    fn gray(shade: u8) -> Color {
+        Color::new(Color::rgb_to_rgba(Color::gray_to_rgb(shade)), None)
+    }
+
+    #[test]
+    fn counts() {
+        // The left tile is all black, the right one half white
+        let pixels = (0..2 * 64)
+            .map(|i| gray(if i % 16 >= 12 { 255 } else { 0 }))
+            .collect();
+        let img = Image::new(16, 8, pixels);
+        let blocks: Vec<_> = (0..2)
+            .map(|i| {
+                let mut block = Block::new(1, (i * 8, 0));
+                block.add_tile(Tile::from_image(&img, i * 8, 0));
+                block
+            })
+            .collect();
+        let blocks: Vec<_> = blocks.iter().map(|block| (0, block)).collect();
+        let palettes = [[gray(0), gray(255), Color::FILLER, Color::FILLER]];
+
+        let usage = palette_usage(&blocks, &[0, 0], &palettes, 0, 4);
+        assert_eq!(usage.len(), 1);
+        assert!(!usage[0].locked);
+        let counts: Vec<_> = usage[0]
+            .colors
+            .iter()
+            .map(|usage| (usage.blocks, usage.pixels))
+            .collect();
+        assert_eq!(counts, [(2, 96), (1, 32), (0, 0), (0, 0)]);
+        assert_eq!(usage[0].blocks.len(), 2);
+        assert_eq!(
+            usage[0].blocks[1],
+            BlockRef {
+                image: 0,
+                x: 8,
+                y: 0,
+                width: 8,
+                height: 8
+            }
+        );
+    }
+
+    #[test]
+    fn json() {
+        let usage = [PaletteUsage {
+            locked: true,
+            colors: vec![ColorUsage {
+                color: gray(255),
+                blocks: 1,
+                pixels: 64,
+            }],
+            blocks: vec![BlockRef {
+                image: 0,
+                x: 0,
+                y: 8,
+                width: 8,
+                height: 8,
+            }],
+        }];
+        let mut output = Vec::new();
+        write(
+            &mut output,
+            &usage,
+            &["a \"b\".png".to_string()],
+            ReportFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"palettes\": [\n\t{\"id\": 0, \"locked\": true, \"colors\": [{\"color\": \"#FFFFFFFF\", \"blocks\": 1, \"pixels\": 64}], \"blocks\": [{\"image\": \"a \\\"b\\\".png\", \"x\": 0, \"y\": 8, \"width\": 8, \"height\": 8}]}\n]}\n"
+        );
+    }
+}
@@ -142,7 +142,7 @@ impl RedundancyTypes {
 /// A 2bpp, Game Boy-format tile.
 // That is, 8 rows of 2 bytes each, with bitplane 0 first.
 /// ("Bitplane N" means "One byte storing bit N of each pixel's index", the leftmost pixel being bit 7.)
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct IndexedTile([u8; 16]);
 
 impl IndexedTile {
@@ -154,7 +154,7 @@ impl IndexedTile {
             for x in 0..8 {
                 let index = palette
                     .iter()
-                    .position(|elem| elem == tile[(x, y)])
+                    .position(|elem| elem.matches(tile[(x, y)]))
                     .expect("Supplied an invalid palette for the tile");
                 assert!(index < 4, "Got non-2bpp index {}", index);
                 bp0 = bp0 << 1 | u8::try_from(index & 1).unwrap();
@@ -188,6 +188,22 @@ impl IndexedTile {
         self.check_all_rows(|y, ry| self.0[y] == (other.0[ry]).reverse_bits())
     }
 
+    pub fn hflipped(&self) -> Self {
+        let mut bytes = self.0;
+        for byte in bytes.iter_mut() {
+            *byte = byte.reverse_bits();
+        }
+        Self(bytes)
+    }
+
+    pub fn vflipped(&self) -> Self {
+        let mut bytes = [0; 16];
+        for y in 0..8 {
+            bytes[y * 2..y * 2 + 2].copy_from_slice(&self.0[(7 - y) * 2..(7 - y) * 2 + 2]);
+        }
+        Self(bytes)
+    }
+
     pub fn write_to(&self, output: &mut impl Write, bpp: u8) -> io::Result<()> {
         if bpp == 1 {
             for i in 0..8 {
@@ -69,3 +69,16 @@ tile_test!(vhflip:
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x03, 0x00,
         0x00
         ]=>!eq,!is_vflip_of,!is_hflip_of,is_vhflip_of);
+
+#[test]
+fn flipped() {
+    let tile = IndexedTile([
+        0x00, 0x00, 0x01, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x70,
+        0x00,
+    ]);
+    assert!(tile.hflipped().is_hflip_of(&tile));
+    assert!(tile.vflipped().is_vflip_of(&tile));
+    assert!(tile.hflipped().vflipped().is_vhflip_of(&tile));
+    assert_eq!(tile.hflipped().hflipped(), tile);
+    assert_eq!(tile.vflipped().vflipped(), tile);
+}
@@ -5,17 +5,21 @@ use logic::Params;
 mod tile;
 mod util;
 
+use args::palette::PaletteFormat;
 use clap::{clap_app, crate_authors, crate_description, crate_version};
+use img::{Curve, Rounding};
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::env;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::num::{NonZeroU16, NonZeroU8};
+use std::path::Path;
 use std::process;
 
 fn main() {
-    // TODO: Color curves (convert colors emitted to the palette files into what would produce them if displayed by a certain console)
-    // TODO: A flag to respect the input PNG's palette IDs
     let mut app = clap_app!(rsgbgfx =>
     (version: crate_version!())
     (author: crate_authors!())
@@ -24,22 +28,41 @@ fn main() {
     (@arg horiz_flip: -H --"horizontal-flip" "Enable discarding tiles by flipping them horizontally")
     (@arg vert_flip: -V --"vertical-flip" "Enable discarding tiles by flipping them vertically")
     (@arg verbose: -v --verbose ... "Enable describing actions taken to stderr, repeat for more details")
-    (@arg sprite: -s --sprite [color] #{0,1} "Enable OAM mode, and possibly force the background color") // TODO: "#n" to pick the nth color in the input palette, otherwise a color
+    (@arg sprite: -s --sprite [color] #{0,1} {str::parse::<args::SpriteColor>} "Enable OAM mode, and possibly pick the transparent color (\"index:n\" for the image palette's nth)")
     (@arg base: -b --base [id] {util::parse_byte} default_value[0] "The base ID for tiles")
-    (@arg bgp: -B --bgp [palette] {util::parse_byte} "This image's DMG palette")
+    (@arg shared: --"shared-color" [color] {str::parse::<args::SharedColor>} conflicts_with[sprite bgp in_pal] "Color to put in slot 0 of all palettes, or \"most-frequent\"")
+    (@arg bgp: -B --bgp [palette] {util::parse_byte} conflicts_with[in_pal sprite] "This image's DMG palette")
     (@arg bpp: -d --depth [bpp] possible_value[1 2] default_value[2] "Number of bits per pixel")
+    (@arg fuzzy: -f --fuzzy [threshold] #{0,1} {util::parse_byte} "Merge colors closer than this threshold (default 8)")
     (@arg height: -h --height [height] default_value[1] "Height in tiles of a \"block\"")
     (@arg width: -w --width [width] default_value[1] "Width in tiles of a \"block\"")
     (@arg out_tiles: -o --"out-tiles" [path] "File name to output the tiles to")
-    (@arg in_pal: -P --"in-palette" [palette] "Palette to use, or \"@path\" to read a RGBA8888 file")
+    (@arg in_pal: -P --"in-palette" [palette] "Palette to use, or \"@path\" to read a palette file")
+    (@arg gen_rest: -G --"generate-rest" requires[in_pal] "Generate palettes for blocks that fit none of the input palette's")
+    (@arg pal_swatch: --"palette-swatch" [size] {util::parse_byte} requires[in_pal] "Read PNG palettes as rows of swatches this many pixels wide")
+    (@arg by_index: -I --"respect-indices" "Put colors in the palette slot given by their index in the input image")
+    (@arg color_order: --"color-order" [order] {str::parse::<logic::ColorOrder>} "How to order colors within generated palettes")
+    (@arg max_pal: --"max-palettes" [count] {str::parse::<NonZeroU16>} "Maximum number of palettes to generate (including -P's, with -G)")
     (@arg out_pal: -p --"out-palette" [path] "File name to output the native palettes to")
+    (@arg out_pal_fmt: --"out-palette-format" [format] possible_value[rgb555 rgba8888 jasc gpl act asm] default_value[rgb555] "Format to output the palettes in")
+    (@arg rounding: --"rgb555-rounding" [rounding] possible_value[truncate nearest scale] default_value[nearest] "How to round colors when converting them to RGB555")
+    (@arg merge_rgb555: --"merge-rgb555" "Merge colors that are identical once converted to RGB555, instead of warning")
+    (@arg curve: -C --"color-curve" [curve] possible_value[identity cgb agb sgb] "Correct the output palette's colors for how this screen displays them")
     (@arg out_pal_rgba8888: --"out-palette-rgba8888" [path] "File name to output the RGBA8888 palettes to")
     (@arg out_pal_map: --"out-palmap" [path] "File name to output the palette map to")
     (@arg out_map: -t --"out-tilemap" [path] "File name to output the tilemap to")
     (@arg out_himap: --"out-himap" [path] "File name to output the \"high\" tilemap to")
     (@arg out_attr: -a --"out-attrmap" [path] "File name to output the GBC attribute map to")
+    (@arg report: --"palette-report" [path] #{0,1} "Report how palettes are used to this file, or stderr")
+    (@arg report_fmt: --"palette-report-format" [format] possible_value[text json] default_value[text] "Format to write the palette report in")
     (@arg in_slices: -S --slices [slices] "Slices to use, or \"@path\" to read a file")
-    (@arg path: * "Path to the input image")
+    (@arg png16: --"png16" [mode] possible_value[round rgb555] default_value[round] "How to read 16-bit PNG colors: rounded to 8 bits, or straight to RGB555")
+    (@arg warn_png16: --"warn-png16" "Warn about 16-bit PNG colors that are distinct even with 8 bits per channel, but end up as the same RGB555 color")
+    (@arg in_fmt: --"input-format" [format] possible_value[png bmp gif ase] "Format of the input images, instead of detecting it from their contents")
+    (@arg ase_layer: --"ase-layer" [name] "Only use this layer of Aseprite files, instead of all visible ones")
+    (@arg ase_frame: --"ase-frame" [frame] {str::parse::<img::AseFrames>} default_value[0] "Frame of Aseprite files to use, or \"all\" to put all frames side by side")
+    (@arg ase_slices: --"ase-slices" conflicts_with[in_slices] "Use the slices defined in Aseprite files")
+    (@arg path: * ... "Paths to the input images, which will share the same palettes")
     );
 
     // By default, `clap` prints to stdout, but we want stderr, so handle printing ourselves
@@ -64,6 +87,85 @@ fn main() {
         Err(e) => e.exit(),
     };
 
+    // With several images, per-image outputs need a "{}" to be replaced with each image's name
+    let paths: Vec<_> = args.values_of_os("path").unwrap().collect();
+    let stem = |input: &OsStr| Path::new(input).file_stem().unwrap_or(input).to_os_string();
+    let per_image_outputs = [
+        "out_tiles",
+        "out_pal_map",
+        "out_map",
+        "out_himap",
+        "out_attr",
+    ];
+    if paths.len() > 1 {
+        if args.is_present("bgp") {
+            eprintln!("Error: -B cannot be used with several images");
+            process::exit(1);
+        }
+        for &name in &per_image_outputs {
+            if let Some(template) = args.value_of_os(name) {
+                if !template.to_string_lossy().contains("{}") {
+                    eprintln!(
+                        "Error: output path \"{}\" must contain \"{{}}\" when processing several images",
+                        template.to_string_lossy()
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+        // ...and that name must then tell the images apart
+        if per_image_outputs.iter().any(|&name| args.is_present(name)) {
+            let mut stems = HashSet::new();
+            for &path in &paths {
+                if !stems.insert(stem(path)) {
+                    eprintln!(
+                        "Error: several images are named \"{}\", so their outputs would overwrite each other",
+                        stem(path).to_string_lossy()
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    // `-` means stdin for images, and stdout for outputs, which can each only be used once
+    if paths.iter().filter(|&&path| util::is_stdio(path)).count() > 1 {
+        eprintln!("Error: standard input can only be read once");
+        process::exit(1);
+    }
+    let stdout_outputs: Vec<_> = [
+        ("out_tiles", "--out-tiles"),
+        ("out_pal", "--out-palette"),
+        ("out_pal_rgba8888", "--out-palette-rgba8888"),
+        ("out_pal_map", "--out-palmap"),
+        ("out_map", "--out-tilemap"),
+        ("out_himap", "--out-himap"),
+        ("out_attr", "--out-attrmap"),
+        ("report", "--palette-report"),
+    ]
+    .iter()
+    .filter(|(name, _)| args.value_of_os(name).is_some_and(util::is_stdio))
+    .map(|&(_, flag)| flag)
+    .collect();
+    if stdout_outputs.len() > 1 {
+        eprintln!(
+            "Error: only one output can be written to standard output, not {}",
+            stdout_outputs.join(", ")
+        );
+        process::exit(1);
+    }
+    let output_path = |name, input: &OsStr| {
+        args.value_of_os(name).map(|template| {
+            if paths.len() == 1 {
+                template.to_os_string()
+            } else {
+                template
+                    .to_string_lossy()
+                    .replace("{}", &stem(input).to_string_lossy())
+                    .into()
+            }
+        })
+    };
+
     let dedup = args.is_present("dedup");
     let horiz_flip = args.is_present("horiz_flip");
     let vert_flip = args.is_present("vert_flip");
@@ -106,36 +208,104 @@ fn main() {
         None => (None, 0),
     };
 
+    let swatch_size = args.value_of("pal_swatch").map(|string| {
+        NonZeroU8::new(util::parse_byte(string).unwrap()).unwrap_or_else(|| {
+            eprintln!("Error: palette swatches cannot be 0 pixels wide");
+            std::process::exit(1);
+        })
+    });
+
     // If a palette was supplied on the CLI, either read the "@file", or process it directly
     let palette = args
         .value_of_os("in_pal")
-        .map(|arg| match args::process_leading_at(arg) {
-            Some(Ok(file)) => args::palette::read(file).unwrap_or_else(|err| {
-                eprintln!("Error processing palette file: {}", err);
-                std::process::exit(1);
-            }),
-            Some(Err(err)) => {
-                eprintln!("Error opening palette file: {}", err);
-                std::process::exit(1);
-            }
+        .map(|arg| match args::leading_at_path(arg) {
+            Some(path) => match File::open(&path) {
+                Ok(file) => args::palette::read(file, &path, swatch_size).unwrap_or_else(|err| {
+                    eprintln!("Error processing palette file: {}", err);
+                    std::process::exit(1);
+                }),
+                Err(err) => {
+                    eprintln!("Error opening palette file: {}", err);
+                    std::process::exit(1);
+                }
+            },
             None => args::palette::parse(arg.to_string_lossy().chars()).unwrap_or_else(|err| {
                 eprintln!("Error reading palette: {}", err);
                 std::process::exit(1);
             }),
         });
-    // TODO: if both fuzziness and palette are given, warn if there is ambiguity
+    let fuzziness = if args.is_present("fuzzy") {
+        Some(
+            args.value_of("fuzzy")
+                .map_or(8, |string| util::parse_byte(string).unwrap()),
+        )
+    } else {
+        None
+    };
+    let rounding: Rounding = args.value_of("rounding").unwrap().parse().unwrap();
+    let sprite = if args.is_present("sprite") {
+        Some(
+            args.value_of("sprite")
+                .map_or(args::SpriteColor::Transparent, |string| {
+                    string.parse().unwrap()
+                }),
+        )
+    } else {
+        None
+    };
+    // There are only 8 OBJ palettes, and no equivalent to the palette map
+    let default_max_palettes = if sprite.is_some() { 8 } else { 65536 };
+    let max_palettes = args
+        .value_of("max_pal")
+        .map_or(default_max_palettes, |string| {
+            usize::from(string.parse::<NonZeroU16>().unwrap().get())
+        });
+
+    let nb_locked = palette.as_ref().map_or(0, tile::Palettes::nb_palettes);
 
     let params = Params {
         verbosity,
 
-        path: args.value_of_os("path").unwrap(),
+        paths: paths.clone(),
 
         block_height,
         block_width,
 
         slices,
         nb_blocks,
+        read: img::ReadOptions {
+            format: args
+                .value_of("in_fmt")
+                .map(|string| string.parse().unwrap()),
+            ase: img::AseOptions {
+                layer: args.value_of("ase_layer").map(str::to_string),
+                frames: args.value_of("ase_frame").unwrap().parse().unwrap(),
+                slices: args.is_present("ase_slices"),
+            },
+            png: img::PngOptions {
+                deep_color: match args.value_of("png16").unwrap() {
+                    "rgb555" => img::DeepColor::Rgb555(rounding),
+                    _ => img::DeepColor::Round,
+                },
+                find_collapsed: args.is_present("warn_png16"),
+                rounding,
+            },
+        },
         palette,
+        max_palettes,
+        generate_rest: args.is_present("gen_rest"),
+        color_order: args
+            .value_of("color_order")
+            .map(|string| string.parse().unwrap()),
+        by_index: args.is_present("by_index"),
+        fuzziness,
+        rounding,
+        merge_rgb555: args.is_present("merge_rgb555"),
+        report: args.is_present("report"),
+        sprite,
+        shared_color: args
+            .value_of("shared")
+            .map(|string| string.parse().unwrap()),
 
         dedup,
         horiz_flip,
@@ -148,125 +318,145 @@ fn main() {
     // Now, process all of that!
 
     // Remember: use `String::from_utf8_lossy` to display file names
-    let (palettes, pal_map, tile_data) = logic::process_file(params).unwrap_or_else(|err| {
+    let (palettes, outputs, report) = logic::process_files(params).unwrap_or_else(|err| {
         eprintln!("error: {}", err);
         process::exit(1);
     });
+    // With `-G`, palettes generated for the blocks that fit none of `-P`'s come after them
+    if verbosity > 0 && args.is_present("gen_rest") && palettes.len() > nb_locked {
+        if palettes.len() - nb_locked == 1 {
+            eprintln!("Generated palette #{}", nb_locked);
+        } else {
+            eprintln!(
+                "Generated palettes #{} to #{}",
+                nb_locked,
+                palettes.len() - 1
+            );
+        }
+    }
 
+    let pal_format: PaletteFormat = args.value_of("out_pal_fmt").unwrap().parse().unwrap();
     let block_size = u16::from(block_height) * u16::from(block_width);
 
     // Output time!
     // TODO: use `BufWriter`s
 
+    let write_palettes = |path, format, kind, palettes: &[_]| match util::create_output(path) {
+        Err(err) => eprintln!("Error opening {}palette output file: {}", kind, err),
+        Ok(mut file) => args::palette::write(&mut file, palettes, format, rounding)
+            .unwrap_or_else(|err| eprintln!("Error writing {}palette: {}", kind, err)),
+    };
     if let Some(path) = args.value_of_os("out_pal") {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening palette output file: {}", err),
-            Ok(mut file) => (|| {
-                for palette in &palettes {
-                    for color in palette {
-                        file.write_all(&color.to_rgb555().to_le_bytes())?;
-                    }
-                }
-                Ok(())
-            })()
-            .unwrap_or_else(|err: io::Error| eprintln!("Error writing palette: {}", err)),
+        match args.value_of("curve") {
+            Some(curve) => {
+                let mut corrected = palettes.clone();
+                curve
+                    .parse::<Curve>()
+                    .unwrap()
+                    .correct_palettes(&mut corrected);
+                write_palettes(path, pal_format, "", &corrected);
+            }
+            None => write_palettes(path, pal_format, "", &palettes),
         }
     }
-
     if let Some(path) = args.value_of_os("out_pal_rgba8888") {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening RGBA8888 palette output file: {}", err),
-            Ok(mut file) => (|| {
-                for palette in &palettes {
-                    for color in palette {
-                        file.write_all(&color.rgba())?;
-                    }
-                }
-                Ok(())
-            })()
-            .unwrap_or_else(|err: io::Error| eprintln!("Error writing RGBA8888 palette: {}", err)),
-        }
+        write_palettes(path, PaletteFormat::Rgba8888, "RGBA8888 ", &palettes);
     }
 
-    if let Some(path) = args.value_of_os("out_tiles") {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening tile output file: {}", err),
-            Ok(mut file) => (|| {
-                for tile in tile_data.tiles() {
-                    tile.write_to(&mut file, bpp)?;
-                }
-                Ok(())
-            })()
-            .unwrap_or_else(|err: io::Error| eprintln!("Error writing tiles: {}", err)),
-        }
+    if let Some(report) = report {
+        let format = args.value_of("report_fmt").unwrap().parse().unwrap();
+        let names: Vec<_> = paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        let result = match args.value_of_os("report") {
+            None => logic::report::write(&mut io::stderr(), &report, &names, format),
+            Some(path) => util::create_output(path)
+                .and_then(|mut file| logic::report::write(&mut file, &report, &names, format)),
+        };
+        result.unwrap_or_else(|err| eprintln!("Error writing palette report: {}", err));
     }
 
-    let pal_map_path = args.value_of_os("out_palmap");
-    if let Some(path) = pal_map_path {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening palette map output file: {}", err),
-            Ok(mut file) => (|| {
-                for entry in &pal_map {
-                    file.write_all(&entry.to_le_bytes())?;
-                }
-                Ok(())
-            })()
-            .unwrap_or_else(|err: io::Error| eprintln!("Error writing palette map: {}", err)),
-        }
+    // Attribute maps only have room for 8 palettes, so the rest must come from the palette map
+    if args.is_present("out_attr") && palettes.len() > 8 && !args.is_present("out_pal_map") {
+        eprintln!(
+            "Warning: {} palettes generated, but palette map not requested",
+            palettes.len()
+        );
     }
-
-    let output_tilemap = |index, file: &mut File| {
-        for base_id in tile_data.base_tile_ids() {
-            for ofs in 0..(block_size) {
-                // Only write the bottom byte
-                file.write_all(&(base_id + ofs).to_le_bytes()[index..=index])?;
+    for (&input, (pal_map, tile_data)) in paths.iter().zip(&outputs) {
+        if let Some(path) = output_path("out_tiles", input) {
+            match util::create_output(&path) {
+                Err(err) => eprintln!("Error opening tile output file: {}", err),
+                Ok(mut file) => (|| {
+                    for tile in tile_data.tiles() {
+                        tile.write_to(&mut file, bpp)?;
+                    }
+                    Ok(())
+                })()
+                .unwrap_or_else(|err: io::Error| eprintln!("Error writing tiles: {}", err)),
             }
         }
-        Ok(())
-    };
-    if let Some(path) = args.value_of_os("out_map") {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening tilemap output file: {}", err),
-            Ok(mut file) => output_tilemap(0, &mut file)
-                .unwrap_or_else(|err: io::Error| eprintln!("Error writing tilemap: {}", err)),
-        }
-    }
-    if let Some(path) = args.value_of_os("out_himap") {
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening high tilemap output file: {}", err),
-            Ok(mut file) => output_tilemap(1, &mut file)
-                .unwrap_or_else(|err: io::Error| eprintln!("Error writing high tilemap: {}", err)),
+
+        if let Some(path) = output_path("out_pal_map", input) {
+            match util::create_output(&path) {
+                Err(err) => eprintln!("Error opening palette map output file: {}", err),
+                Ok(mut file) => (|| {
+                    for entry in pal_map {
+                        file.write_all(&entry.to_le_bytes())?;
+                    }
+                    Ok(())
+                })()
+                .unwrap_or_else(|err: io::Error| eprintln!("Error writing palette map: {}", err)),
+            }
         }
-    }
 
-    if let Some(path) = args.value_of_os("out_attrmap") {
-        // TODO: warn if more than 8 palettes and palette map is not demanded
-        if palettes.len() > 8 && !args.is_present("out-palmap") {
-            eprintln!(
-                "Warning: {} palettes generated, but palette map not requested",
-                palettes.len()
-            );
+        let output_tilemap = |index, file: &mut dyn Write| {
+            for base_id in tile_data.base_tile_ids() {
+                for ofs in 0..(block_size) {
+                    // Only write the bottom byte
+                    file.write_all(&(base_id + ofs).to_le_bytes()[index..=index])?;
+                }
+            }
+            Ok(())
+        };
+        if let Some(path) = output_path("out_map", input) {
+            match util::create_output(&path) {
+                Err(err) => eprintln!("Error opening tilemap output file: {}", err),
+                Ok(mut file) => output_tilemap(0, &mut file)
+                    .unwrap_or_else(|err: io::Error| eprintln!("Error writing tilemap: {}", err)),
+            }
+        }
+        if let Some(path) = output_path("out_himap", input) {
+            match util::create_output(&path) {
+                Err(err) => eprintln!("Error opening high tilemap output file: {}", err),
+                Ok(mut file) => output_tilemap(1, &mut file).unwrap_or_else(|err: io::Error| {
+                    eprintln!("Error writing high tilemap: {}", err)
+                }),
+            }
         }
 
-        match File::open(path) {
-            Err(err) => eprintln!("Error opening attrmap output file: {}", err),
-            Ok(mut file) => (|| {
-                assert_eq!(tile_data.attrs().len(), pal_map.len());
+        if let Some(path) = output_path("out_attr", input) {
+            match util::create_output(&path) {
+                Err(err) => eprintln!("Error opening attrmap output file: {}", err),
+                Ok(mut file) => (|| {
+                    assert_eq!(tile_data.attrs().len(), pal_map.len());
 
-                for (attr, pal) in tile_data.attrs().iter().zip(pal_map.iter()) {
-                    for _ in 0..block_size {
-                        let pal_id = if args.is_present("out-palmap") {
-                            0
-                        } else {
-                            u8::try_from(pal & 7).unwrap()
-                        };
+                    for (attr, pal) in tile_data.attrs().iter().zip(pal_map) {
+                        for _ in 0..block_size {
+                            let pal_id = if args.is_present("out_pal_map") {
+                                0
+                            } else {
+                                u8::try_from(pal & 7).unwrap()
+                            };
 
-                        file.write_all(&[*attr | pal_id])?;
+                            file.write_all(&[*attr | pal_id])?;
+                        }
                     }
-                }
-                Ok(())
-            })()
-            .unwrap_or_else(|err: io::Error| eprintln!("Error writing attrmap: {}", err)),
+                    Ok(())
+                })()
+                .unwrap_or_else(|err: io::Error| eprintln!("Error writing attrmap: {}", err)),
+            }
         }
     }
 }
@@ -12,7 +12,9 @@ use arrayvec::ArrayVec;
 pub struct Tile<'a> {
     pixels: [[&'a Color; 8]; 8],
     // Coordinates (for reporting location in errors)
+    #[allow(dead_code)] // Not reported anywhere yet
     x: u32,
+    #[allow(dead_code)]
     y: u32,
 }
 
@@ -20,10 +22,8 @@ impl<'a> Tile<'a> {
     pub fn from_image(img: &'a Image, x: u32, y: u32) -> Self {
         Self {
             pixels: (y..(y + 8))
-                .into_iter()
                 .map(|y| {
                     (x..(x + 8))
-                        .into_iter()
                         .map(|x| &img[(x, y)])
                         .collect::<ArrayVec<_>>()
                         .into_inner()
@@ -6,43 +6,64 @@ use std::ops::Deref;
 #[derive(Debug)]
 pub struct Palettes {
     colors: Vec<[Color; 4]>,
-    nb_colors: u16,
+    lens: Vec<u8>, // How many colors of each palette were actually specified
 }
 
 impl Palettes {
     pub fn new() -> Self {
         Self {
             colors: Vec::new(),
-            nb_colors: 0,
+            lens: Vec::new(),
         }
     }
 
-    /// Append a new color.
+    /// Ensure there is room for a new palette, and append it, padded with the filler color.
+    fn new_palette(&mut self) -> Result<&mut [Color; 4], ()> {
+        // Keep the amount of palettes in the u16 range
+        if self.colors.len() == 65536 {
+            return Err(());
+        }
+        self.colors
+            .push([Color::FILLER, Color::FILLER, Color::FILLER, Color::FILLER]);
+        self.lens.push(0);
+        Ok(self.colors.last_mut().unwrap())
+    }
+
+    /// Append a new color, starting a new palette if the last one is full.
     /// The amount of 4-color palettes is capped in the 16-bit range, exceeding it is the Err() case.
     pub fn push(&mut self, color: Color) -> Result<(), ()> {
-        if self.nb_colors % 4 == 0 {
-            // Keep the amount of palettes in the u16 range
-            if self.colors.len() == 65536 {
-                return Err(());
-            }
-            self.colors
-                .resize_with(self.colors.len() + 1, Default::default);
+        if self.lens.last().copied().unwrap_or(4) == 4 {
+            self.new_palette()?;
         }
-        self.colors.last_mut().unwrap()[usize::from(self.nb_colors % 4)] = color;
-        self.nb_colors += 1;
+        let len = self.lens.last_mut().unwrap();
+        self.colors.last_mut().unwrap()[usize::from(*len)] = color;
+        *len += 1;
         Ok(())
     }
 
-    pub fn nb_colors(&self) -> u16 {
-        self.nb_colors
+    /// Append a whole palette of up to 4 colors; unused entries are padded with the filler color.
+    /// As with `push`, exceeding the 16-bit range of palettes is the Err() case.
+    pub fn push_palette(&mut self, palette: &[Color]) -> Result<(), ()> {
+        assert!(
+            !palette.is_empty() && palette.len() <= 4,
+            "Palettes must contain between 1 and 4 colors, not {}",
+            palette.len()
+        );
+
+        let slots = self.new_palette()?;
+        slots[..palette.len()].clone_from_slice(palette);
+        *self.lens.last_mut().unwrap() = palette.len().try_into().unwrap();
+        Ok(())
     }
 
-    pub fn nb_palettes(&self) -> u16 {
-        self.colors.len().try_into().unwrap()
+    /// How many palettes there are; up to 65536, so this doesn't always fit in a u16
+    pub fn nb_palettes(&self) -> usize {
+        self.colors.len()
     }
 
-    pub fn colors(self) -> Vec<[Color; 4]> {
-        self.colors
+    /// How many colors were specified for a given palette (the rest is padding)
+    pub fn palette_len(&self, id: usize) -> usize {
+        self.lens[id].into()
     }
 }
 
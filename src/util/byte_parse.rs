@@ -48,7 +48,7 @@ pub fn parse_byte(string: &str) -> Result<u8, ByteParseError> {
         None => (10, false),
     };
 
-    if !got_digit && chars.peek() == None {
+    if !got_digit && chars.peek().is_none() {
         return Err(ByteParseError::Empty);
     }
 
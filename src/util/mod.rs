@@ -2,3 +2,5 @@ mod byte_parse;
 pub use byte_parse::parse_byte;
 mod read_chars;
 pub use read_chars::{CharReader, CharReaderError};
+mod stdio;
+pub use stdio::{create_output, is_stdio};
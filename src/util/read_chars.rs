@@ -22,7 +22,7 @@ impl<R: Read> Iterator for CharReader<R> {
             let first = first?;
             let mut bytes = [first, 0, 0, 0];
             let n = usize::try_from(first.leading_ones()).unwrap();
-            if n >= 2 && n <= 4 {
+            if (2..=4).contains(&n) {
                 // `n` indicates the total amount of bytes, but the first one has already been read
                 for b in bytes.iter_mut().skip(1).take(n - 1) {
                     *b = match self.bytes.next() {
@@ -0,0 +1,17 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Whether a path actually means stdin or stdout, depending on the direction
+pub fn is_stdio(path: &OsStr) -> bool {
+    path == "-"
+}
+
+/// Creates an output file, or locks stdout if the path is `-`.
+pub fn create_output(path: &OsStr) -> io::Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdout().lock()))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}